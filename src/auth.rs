@@ -1,21 +1,54 @@
+use crate::cache::TokenCache;
 use crate::errors::ReddSaverError;
+use crate::http::Http;
+use crate::ratelimit::RateLimiter;
+use crate::totp::Totp;
+use crate::utils::unix_timestamp;
 
-use log::debug;
+use log::{debug, info, warn};
 use reqwest::header::{AUTHORIZATION, USER_AGENT};
+use reqwest::{RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use url::Url;
+
+/// Scopes requested when authorizing through the browser. `identity` and `read` are needed
+/// to look up the user, `history` to list saved posts and `save` to unsave them
+static OAUTH_SCOPES: &str = "identity history read save";
+
+/// Renew the access token when it is this close (in seconds) to expiring, so that
+/// requests in flight do not race against the expiry
+static EXPIRY_MARGIN: u64 = 120;
+
+/// Wait for the next one-time password if the current one expires in fewer seconds than this
+static TOTP_MIN_VALIDITY: u64 = 3;
+/// Words in the login errors that tell the one-time password was rejected, rather than the
+/// password itself
+static TOTP_ERROR_WORDS: [&str; 3] = ["otp", "2fa", "two-factor"];
+/// Number of times a request is retried after Reddit responds with HTTP 429
+static RATE_LIMIT_RETRIES: u32 = 5;
 
 /// To generate the Reddit Client ID and secret, go to reddit [preferences](https://www.reddit.com/prefs/apps)
 pub struct Client<'a> {
+    /// Shared HTTP client used to talk to Reddit
+    http: &'a Http,
     /// Client ID for the application
     client_id: &'a str,
     /// Client Secret for the application
     client_secret: &'a str,
     /// Login username
     username: &'a str,
-    /// Login password
-    password: &'a str,
-    /// Unique User agent string
+    /// Login password, not required if a refresh token is available
+    password: Option<&'a str>,
+    /// Refresh token obtained from a previous `reddsaver login`
+    refresh_token: Option<&'a str>,
+    /// Generator for the one-time password of accounts with 2FA enabled
+    totp: Option<&'a Totp>,
+    /// User agent string sent with every request made on behalf of this account
     user_agent: &'a str,
 }
 
@@ -29,50 +62,368 @@ pub struct Auth {
     expires_in: i32,
     /// Scope of the access token. This app requires * scope
     scope: String,
+    /// Long lived token returned when authorizing with `duration=permanent`
+    pub refresh_token: Option<String>,
+}
+
+/// An access token along with the absolute time at which it stops being valid
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Token {
+    pub auth: Auth,
+    /// Expiry time of the access token as seconds since the UNIX epoch
+    pub expires_at: u64,
+}
+
+impl Token {
+    fn new(auth: Auth) -> Self {
+        let expires_at = unix_timestamp() + auth.expires_in.max(0) as u64;
+        Self { auth, expires_at }
+    }
+
+    /// Check if the token is still usable, leaving some headroom before the actual expiry
+    pub fn is_fresh(&self) -> bool {
+        unix_timestamp() + EXPIRY_MARGIN < self.expires_at
+    }
+}
+
+/// The access token endpoint responds with HTTP 200 even when the credentials are wrong,
+/// so we need to look at the body to find out whether the login succeeded
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum TokenResponse {
+    Success(Auth),
+    Failure { error: Value },
 }
 
 impl<'a> Client<'a> {
     pub fn new(
+        http: &'a Http,
         id: &'a str,
         secret: &'a str,
         username: &'a str,
-        password: &'a str,
+        password: Option<&'a str>,
         agent: &'a str,
     ) -> Self {
         Self {
-            client_id: &id,
-            client_secret: &secret,
-            username: &username,
-            password: &password,
-            user_agent: &agent,
+            http,
+            client_id: id,
+            client_secret: secret,
+            username,
+            password,
+            refresh_token: None,
+            totp: None,
+            user_agent: agent,
+        }
+    }
+
+    /// Use a stored refresh token instead of the password grant when authenticating
+    pub fn refresh_token(mut self, token: Option<&'a str>) -> Self {
+        self.refresh_token = token;
+        self
+    }
+
+    /// Append a one-time password to the password when logging in with the password grant
+    pub fn totp(mut self, totp: Option<&'a Totp>) -> Self {
+        self.totp = totp;
+        self
+    }
+
+    /// Obtain an access token using the refresh token if one is available, falling back
+    /// to the password grant otherwise
+    pub async fn authenticate(&self) -> Result<Auth, ReddSaverError> {
+        match self.refresh_token {
+            Some(token) => self.refresh(token).await,
+            None => self.login().await,
         }
     }
 
     pub async fn login(&self) -> Result<Auth, ReddSaverError> {
-        let basic_token = base64::encode(format!("{}:{}", self.client_id, self.client_secret));
-        let grant_type = String::from("password");
+        let password = match self.password {
+            Some(p) => p,
+            None => {
+                return Err(ReddSaverError::MissingCredentials(String::from(
+                    self.username,
+                )))
+            }
+        };
 
         let mut body = HashMap::new();
         body.insert("username", self.username);
-        body.insert("password", self.password);
-        body.insert("grant_type", &grant_type);
+        body.insert("password", password);
+        body.insert("grant_type", "password");
+
+        let totp = match self.totp {
+            Some(t) => t,
+            None => return self.request_token(&body).await,
+        };
+
+        // don't send a code that will have rolled over by the time reddit checks it
+        let remaining = totp.seconds_remaining(unix_timestamp());
+        if remaining < TOTP_MIN_VALIDITY {
+            debug!(
+                "One-time password expires in {}s, waiting for the next one",
+                remaining
+            );
+            tokio::time::delay_for(Duration::from_secs(remaining)).await;
+        }
+
+        // reddit expects the one-time password appended to the password as <password>:<code>
+        // the codes of adjacent time steps are only tried when the code was rejected, as every
+        // failed login counts towards locking the account
+        let mut last_error = None;
+        for code in totp.codes_around(unix_timestamp()) {
+            let password_with_code = format!("{}:{}", password, code);
+            let mut body_with_code = body.clone();
+            body_with_code.insert("password", &password_with_code);
+
+            match self.request_token(&body_with_code).await {
+                Err(ReddSaverError::LoginFailed(e)) if is_rejected_code(&e) => {
+                    debug!("One-time password was rejected, trying adjacent time step");
+                    last_error = Some(ReddSaverError::LoginFailed(e));
+                }
+                result => return result,
+            }
+        }
+
+        Err(last_error.unwrap())
+    }
+
+    /// Exchange a refresh token for a new access token
+    pub async fn refresh(&self, refresh_token: &str) -> Result<Auth, ReddSaverError> {
+        let mut body = HashMap::new();
+        body.insert("grant_type", "refresh_token");
+        body.insert("refresh_token", refresh_token);
+
+        let mut auth = self.request_token(&body).await?;
+        // reddit does not send the refresh token again, keep it around for the next renewal
+        if auth.refresh_token.is_none() {
+            auth.refresh_token = Some(String::from(refresh_token));
+        }
+
+        Ok(auth)
+    }
+
+    /// Build the URL the user has to visit to grant reddsaver access to their account
+    pub fn authorize_url(&self, redirect_uri: &str, state: &str) -> Result<Url, ReddSaverError> {
+        let url = Url::parse_with_params(
+            &self.http.endpoints().authorize(),
+            &[
+                ("client_id", self.client_id),
+                ("response_type", "code"),
+                ("state", state),
+                ("redirect_uri", redirect_uri),
+                // ask for a refresh token so that we can log in again without the browser
+                ("duration", "permanent"),
+                ("scope", OAUTH_SCOPES),
+            ],
+        )?;
+
+        Ok(url)
+    }
+
+    /// Exchange the one-time code received on the redirect URI for an access token
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+    ) -> Result<Auth, ReddSaverError> {
+        let mut body = HashMap::new();
+        body.insert("grant_type", "authorization_code");
+        body.insert("code", code);
+        body.insert("redirect_uri", redirect_uri);
+
+        self.request_token(&body).await
+    }
 
-        let client = reqwest::Client::new();
-        let auth = client
-            .post("https://www.reddit.com/api/v1/access_token")
+    async fn request_token(&self, body: &HashMap<&str, &str>) -> Result<Auth, ReddSaverError> {
+        let basic_token = base64::encode(format!("{}:{}", self.client_id, self.client_secret));
+
+        let request = self
+            .http
+            .post(&self.http.endpoints().access_token())
             .header(USER_AGENT, self.user_agent)
             // base64 encoded <clientID>:<clientSecret> should be sent as a basic token
             // along with the body of the message
             .header(AUTHORIZATION, format!("Basic {}", basic_token))
             // make sure the username and password is sent as form encoded values
             // the API does not accept JSON body when trying to obtain a bearer token
-            .form(&body)
-            .send()
+            .form(body);
+        let response = self
+            .http
+            .send(request)
             .await?
-            .json::<Auth>()
+            .json::<TokenResponse>()
             .await?;
 
-        debug!("Access token is: {}", auth.access_token);
-        Ok(auth)
+        match response {
+            TokenResponse::Success(auth) => {
+                debug!("Access token is: {}", auth.access_token);
+                Ok(auth)
+            }
+            TokenResponse::Failure { error } => Err(ReddSaverError::LoginFailed(error.to_string())),
+        }
+    }
+}
+
+/// Whether the error of a failed login tells that the one-time password was wrong or expired
+fn is_rejected_code(error: &str) -> bool {
+    let error = error.to_lowercase();
+    TOTP_ERROR_WORDS.iter().any(|word| error.contains(word))
+}
+
+/// Holds the access token shared by all API calls and renews it when it is about to
+/// expire or gets rejected by Reddit
+pub struct Session<'a> {
+    client: Client<'a>,
+    token: Mutex<Token>,
+    /// On-disk cache the token is persisted to between runs
+    cache: Option<TokenCache>,
+    /// Rate limit budget shared by all API calls made for the account
+    limiter: RateLimiter,
+}
+
+impl<'a> Session<'a> {
+    /// Start a new session, reusing the cached token if it is still valid and logging
+    /// in using the client otherwise
+    pub async fn new(
+        client: Client<'a>,
+        cache: Option<TokenCache>,
+    ) -> Result<Session<'a>, ReddSaverError> {
+        let token = match cache.as_ref().and_then(|c| c.load()) {
+            Some(token) => {
+                info!("Reusing cached access token for {}", client.username);
+                token
+            }
+            None => {
+                let token = Token::new(client.authenticate().await?);
+                if let Some(c) = &cache {
+                    c.store(&token);
+                }
+                token
+            }
+        };
+
+        Ok(Self {
+            client,
+            token: Mutex::new(token),
+            cache,
+            limiter: RateLimiter::new(),
+        })
+    }
+
+    /// Return a valid access token, renewing the current one if it is about to expire
+    pub async fn access_token(&self) -> Result<String, ReddSaverError> {
+        let mut token = self.token.lock().await;
+        if !token.is_fresh() {
+            info!("Access token is about to expire, renewing it");
+            *token = self.authenticate(&token).await?;
+        }
+
+        Ok(token.auth.access_token.clone())
+    }
+
+    /// Renew the access token after `rejected` was refused by the API. If another request
+    /// already renewed it in the meantime, the newer token is returned as is
+    async fn renew(&self, rejected: &str) -> Result<String, ReddSaverError> {
+        let mut token = self.token.lock().await;
+        if token.auth.access_token == rejected {
+            if let Some(c) = &self.cache {
+                c.invalidate();
+            }
+            *token = self.authenticate(&token).await?;
+        }
+
+        Ok(token.auth.access_token.clone())
+    }
+
+    /// Obtain a new token, preferring the refresh token held by the current one
+    async fn authenticate(&self, current: &Token) -> Result<Token, ReddSaverError> {
+        let auth = match &current.auth.refresh_token {
+            Some(refresh_token) => self.client.refresh(refresh_token).await?,
+            None => self.client.authenticate().await?,
+        };
+        let token = Token::new(auth);
+        if let Some(c) = &self.cache {
+            c.store(&token);
+        }
+
+        Ok(token)
+    }
+
+    /// User agent string of the account this session belongs to
+    pub fn user_agent(&self) -> &str {
+        self.client.user_agent
+    }
+
+    /// Shared HTTP client to build requests with
+    pub fn http(&self) -> &'a Http {
+        self.client.http
+    }
+
+    /// Send an authenticated request built by `build`, waiting for the rate limit to allow it.
+    /// The request is built again and retried once with a new access token if the API
+    /// responds with HTTP 401, and after backing off if it responds with HTTP 429
+    pub async fn execute<F>(&self, build: F) -> Result<Response, ReddSaverError>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut access_token = self.access_token().await?;
+        let mut renewed = false;
+        let mut attempt = 0;
+
+        loop {
+            self.limiter.acquire().await;
+            let response = self.send(build(), &access_token).await?;
+            self.limiter.update(response.headers()).await;
+
+            match response.status() {
+                StatusCode::UNAUTHORIZED if !renewed => {
+                    warn!("Access token was rejected by Reddit, logging in again");
+                    access_token = self.renew(&access_token).await?;
+                    renewed = true;
+                }
+                StatusCode::TOO_MANY_REQUESTS => {
+                    if attempt >= RATE_LIMIT_RETRIES {
+                        return Err(ReddSaverError::RateLimited);
+                    }
+                    self.limiter.throttle(response.headers(), attempt).await;
+                    attempt += 1;
+                }
+                _ => return Ok(response),
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        request: RequestBuilder,
+        access_token: &str,
+    ) -> Result<Response, ReddSaverError> {
+        let request = request
+            .bearer_auth(access_token)
+            // reddit will forbid you from accessing the API if the provided user agent is not unique
+            .header(USER_AGENT, self.user_agent());
+
+        self.http().send(request).await
+    }
+}
+
+impl fmt::Debug for Session<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("username", &self.client.username)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_rejected_codes_are_told_apart() {
+        assert!(is_rejected_code("\"WRONG_OTP\""));
+        assert!(is_rejected_code("Invalid two-factor code"));
+        assert!(!is_rejected_code("\"invalid_grant\""));
     }
 }
@@ -0,0 +1,85 @@
+use crate::auth::Token;
+use crate::errors::ReddSaverError;
+use crate::utils::write_private_file;
+
+use log::{debug, warn};
+use std::fs;
+use std::path::PathBuf;
+
+/// Directory under the user's cache directory where access tokens are kept
+static CACHE_DIRECTORY: &str = "reddsaver";
+
+/// Per-account cache of the access token, so that runs in quick succession
+/// do not have to log in to Reddit every time
+#[derive(Debug)]
+pub struct TokenCache {
+    path: PathBuf,
+}
+
+impl TokenCache {
+    pub fn new(username: &str) -> Result<Self, ReddSaverError> {
+        let mut path = dirs::cache_dir().ok_or(ReddSaverError::ConfigDirNotFound)?;
+        path.push(CACHE_DIRECTORY);
+        path.push(format!("{}.token.json", username.to_lowercase()));
+
+        Ok(Self { path })
+    }
+
+    /// Return the cached token if it is present and has not expired yet
+    pub fn load(&self) -> Option<Token> {
+        let contents = fs::read_to_string(&self.path).ok()?;
+        match serde_json::from_str::<Token>(&contents) {
+            Ok(token) if token.is_fresh() => {
+                debug!("Using cached access token from {}", self.path.display());
+                Some(token)
+            }
+            Ok(_) => {
+                debug!("Cached access token in {} has expired", self.path.display());
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "Ignoring unreadable token cache {}: {}",
+                    self.path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Save the token, readable only by the current user
+    pub fn store(&self, token: &Token) {
+        let result = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                let contents = serde_json::to_vec(token)?;
+                write_private_file(&self.path, &contents)
+            });
+
+        // failing to cache the token is not fatal, we just log in again next time
+        if let Err(e) = result {
+            warn!(
+                "Could not cache access token in {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Remove the cached token, e.g. after the API rejected it
+    pub fn invalidate(&self) {
+        if self.path.exists() {
+            debug!("Removing cached access token {}", self.path.display());
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!(
+                    "Could not remove token cache {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,947 @@
+use crate::download::VideoSettings;
+use crate::endpoints::{self, Endpoints};
+use crate::errors::ReddSaverError;
+use crate::fixtures::Mode;
+use crate::http::HttpSettings;
+use crate::limits::{DownloadLimits, DEFAULT_MAX_DOWNLOADS, DEFAULT_MAX_PER_HOST};
+use crate::manifest::VideoQuality;
+use crate::mux::Muxer;
+use crate::retry::{RetryPolicy, DEFAULT_RETRY_ATTEMPTS, DEFAULT_RETRY_DELAY};
+use crate::secrets::{Secret, SecretSpec};
+use crate::utils::get_user_agent_string;
+
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Default directory to save the media to
+pub static DEFAULT_DATA_DIR: &str = "data";
+/// Default .env style file to read secrets from
+pub static DEFAULT_ENV_FILE: &str = ".env";
+/// Redirect URI the README asks users to register for their application
+static DEFAULT_REDIRECT_URI: &str = "http://localhost:8080";
+/// Default number of seconds to wait for a connection to be established
+static DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+/// Default number of seconds to wait for the server to send data
+static DEFAULT_READ_TIMEOUT: u64 = 30;
+/// Default number of idle connections kept open per host
+static DEFAULT_POOL_MAX_IDLE: usize = 8;
+/// Name of the configuration file looked up in the user's configuration directory
+static CONFIG_FILE_NAME: &str = "reddsaver.toml";
+
+/// Settings that can be given in the configuration file, either at the top level or in a
+/// `[profile.<name>]` section
+///
+/// ```toml
+/// data_dir = "data"
+/// human_readable = true
+/// concurrent = true
+/// read_timeout = 60
+/// proxy = "socks5://localhost:1080"
+/// max_downloads = 8
+///
+/// [host_limits]
+/// "imgur.com" = 2
+///
+/// [[accounts]]
+/// name = "main"
+/// client_id = "<client_id>"
+/// client_secret_file = "/run/secrets/reddit_client_secret"
+/// username = "<username>"
+/// password_command = "pass show reddit/main"
+/// data_dir = "data/main"
+/// subreddits = ["pics", "earthporn"]
+/// unsave = true
+///
+/// [profile.pics]
+/// subreddits = ["pics"]
+/// dry_run = true
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FileSettings {
+    pub env_file: Option<String>,
+    pub data_dir: Option<String>,
+    pub dry_run: Option<bool>,
+    pub human_readable: Option<bool>,
+    pub subreddits: Option<Vec<String>>,
+    pub unsave: Option<bool>,
+    /// Process all accounts at the same time instead of one after another
+    pub concurrent: Option<bool>,
+    pub redirect_uri: Option<String>,
+    /// Override the user agent derived from the username
+    pub user_agent: Option<String>,
+    /// Seconds to wait for a connection to be established
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for the server to send the next piece of data
+    pub read_timeout: Option<u64>,
+    /// HTTP, HTTPS or SOCKS5 proxy to send all requests through
+    pub proxy: Option<String>,
+    /// PEM files with additional certificate authorities to trust, e.g. for a TLS intercepting proxy
+    pub ca_certs: Option<Vec<String>>,
+    /// Maximum number of idle connections kept open per host
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Maximum number of media downloaded at the same time
+    pub max_downloads: Option<usize>,
+    /// Maximum number of media downloaded at the same time from a host without a limit of its own
+    pub max_per_host: Option<usize>,
+    /// Maximum number of media downloaded at the same time from a domain and its subdomains
+    pub host_limits: Option<HashMap<String, usize>>,
+    /// Number of times a media download is attempted before giving up on it
+    pub retry_attempts: Option<u32>,
+    /// Milliseconds to wait before the first retry of a failed download
+    pub retry_delay: Option<u64>,
+    /// Highest quality of Reddit videos to download, `best` or a height like `720p`
+    pub video_quality: Option<VideoQuality>,
+    /// How the separate video and audio tracks of Reddit videos are merged
+    pub muxer: Option<Muxer>,
+    /// Base URLs of the remote services, only meant to be changed for testing
+    pub reddit_url: Option<String>,
+    pub oauth_url: Option<String>,
+    pub reddit_media_url: Option<String>,
+    pub gfycat_api_url: Option<String>,
+    pub redgifs_api_url: Option<String>,
+    pub giphy_media_url: Option<String>,
+    /// Directory to record all responses to
+    pub record: Option<String>,
+    /// Directory to replay all responses from instead of using the network
+    pub replay: Option<String>,
+    /// Reddit accounts to download the saved media of
+    pub accounts: Option<Vec<AccountConfig>>,
+}
+
+/// Contents of a reddsaver configuration file
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    /// Settings at the top level of the file
+    pub settings: FileSettings,
+    /// Named profiles that override the top level settings
+    pub profiles: HashMap<String, FileSettings>,
+}
+
+/// The layer a setting was taken from, in increasing order of precedence
+#[derive(Debug, Clone, PartialEq)]
+pub enum Layer {
+    Default,
+    File(String),
+    Profile(String),
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::Default => write!(f, "default"),
+            Layer::File(path) => write!(f, "config file {}", path),
+            Layer::Profile(name) => write!(f, "profile {}", name),
+            Layer::Env(name) => write!(f, "environment variable {}", name),
+            Layer::Flag(name) => write!(f, "flag --{}", name),
+        }
+    }
+}
+
+/// A configuration value along with the layer it was taken from
+#[derive(Debug)]
+pub struct Setting<T> {
+    pub value: T,
+    pub layer: Layer,
+}
+
+impl<T> Setting<T> {
+    fn new(value: T) -> Self {
+        Self {
+            value,
+            layer: Layer::Default,
+        }
+    }
+
+    /// Override the value if the given layer sets it
+    fn merge(&mut self, value: Option<T>, layer: &Layer) {
+        if let Some(v) = value {
+            self.value = v;
+            self.layer = layer.clone();
+        }
+    }
+}
+
+/// Fully resolved configuration. Every value is looked up in the following order, with
+/// later layers taking precedence over earlier ones:
+/// 1. built-in defaults
+/// 2. the top level of the configuration file
+/// 3. the `[profile.<name>]` section selected with `--profile`
+/// 4. environment variables (including the ones from the .env file)
+/// 5. command line flags
+#[derive(Debug)]
+pub struct Config {
+    /// Configuration file the settings were read from, if any
+    pub config_file: Option<String>,
+    /// Profile selected from the configuration file, if any
+    pub profile: Option<String>,
+    pub env_file: Setting<String>,
+    pub data_directory: Setting<String>,
+    pub dry_run: Setting<bool>,
+    pub human_readable: Setting<bool>,
+    pub subreddits: Setting<Option<Vec<String>>>,
+    pub unsave: Setting<bool>,
+    pub concurrent: Setting<bool>,
+    pub redirect_uri: Setting<String>,
+    pub user_agent: Setting<Option<String>>,
+    pub connect_timeout: Setting<u64>,
+    pub read_timeout: Setting<u64>,
+    pub proxy: Setting<Option<String>>,
+    pub ca_certs: Setting<Vec<String>>,
+    pub pool_max_idle_per_host: Setting<usize>,
+    pub max_downloads: Setting<usize>,
+    pub max_per_host: Setting<usize>,
+    /// Per-host limits in addition to the built-in ones
+    pub host_limits: Setting<HashMap<String, usize>>,
+    pub retry_attempts: Setting<u32>,
+    pub retry_delay: Setting<u64>,
+    pub video_quality: Setting<VideoQuality>,
+    pub muxer: Setting<Muxer>,
+    pub reddit_url: Setting<String>,
+    pub oauth_url: Setting<String>,
+    pub reddit_media_url: Setting<String>,
+    pub gfycat_api_url: Setting<String>,
+    pub redgifs_api_url: Setting<String>,
+    pub giphy_media_url: Setting<String>,
+    /// Directory to record all responses to
+    pub record: Setting<Option<String>>,
+    /// Directory to replay all responses from instead of using the network
+    pub replay: Setting<Option<String>>,
+    pub accounts: Setting<Vec<AccountConfig>>,
+}
+
+/// A single account as written in the configuration file
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    /// Label for the account, defaults to the username
+    pub name: Option<String>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub client_secret_file: Option<String>,
+    pub client_secret_command: Option<String>,
+    pub username: String,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+    pub password_command: Option<String>,
+    pub totp_secret: Option<String>,
+    pub totp_secret_file: Option<String>,
+    pub totp_secret_command: Option<String>,
+    /// Directory to save the media of this account to, defaults to the global data directory
+    pub data_dir: Option<String>,
+    /// Download media from these subreddits only
+    pub subreddits: Option<Vec<String>>,
+    /// Unsave posts of this account after processing
+    pub unsave: Option<bool>,
+    /// User agent to identify as when making requests for this account
+    pub user_agent: Option<String>,
+}
+
+/// Settings that apply to an account when they are not set for the account itself
+#[derive(Debug)]
+pub struct AccountDefaults {
+    pub data_directory: String,
+    pub subreddits: Option<Vec<String>>,
+    pub unsave: bool,
+    /// User agent to use instead of the one derived from the username
+    pub user_agent: Option<String>,
+}
+
+/// Fully resolved account, with its secrets read from wherever they were stored
+#[derive(Debug)]
+pub struct Account {
+    /// Label used to refer to the account in logs and on the command line
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: Secret,
+    pub username: String,
+    pub password: Option<Secret>,
+    pub totp_secret: Option<Secret>,
+    pub data_directory: String,
+    pub subreddits: Option<Vec<String>>,
+    pub unsave: bool,
+    /// User agent used for every request made for this account, computed once per run
+    pub user_agent: String,
+}
+
+impl ConfigFile {
+    pub fn load(path: &str) -> Result<Self, ReddSaverError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|_| ReddSaverError::CouldNotReadConfig(String::from(path)))?;
+        Self::parse(&contents)
+    }
+
+    fn parse(contents: &str) -> Result<Self, ReddSaverError> {
+        let mut table: toml::value::Table = toml::from_str(contents)?;
+
+        // profiles use the same keys as the top level, so split them out before deserializing
+        let profiles = match table.remove("profile") {
+            Some(p) => p.try_into::<HashMap<String, FileSettings>>()?,
+            None => HashMap::new(),
+        };
+        let settings = toml::Value::Table(table).try_into::<FileSettings>()?;
+
+        Ok(Self { settings, profiles })
+    }
+
+    /// Location of the configuration file in the user's configuration directory,
+    /// e.g. `~/.config/reddsaver/reddsaver.toml` on Linux
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("reddsaver").join(CONFIG_FILE_NAME))
+    }
+}
+
+impl Config {
+    /// Resolve the configuration from the configuration file, the environment and the command
+    /// line flags. This also loads the .env file, so that its variables are part of the environment
+    pub fn load(matches: &ArgMatches) -> Result<Self, ReddSaverError> {
+        let mut config = Config::defaults();
+
+        // use the configuration file passed on the command line, or the one in the
+        // configuration directory if it exists
+        let config_file = match matches.value_of("config") {
+            Some(path) => Some(String::from(path)),
+            None => ConfigFile::default_path()
+                .filter(|p| p.exists())
+                .map(|p| p.to_string_lossy().into_owned()),
+        };
+
+        if let Some(path) = &config_file {
+            let file = ConfigFile::load(path)?;
+            config.merge_config_file(path, file, matches.value_of("profile"))?;
+        } else if let Some(name) = matches.value_of("profile") {
+            return Err(ReddSaverError::ProfileNotFound(String::from(name)));
+        }
+        config.config_file = config_file;
+
+        // the env file has to be known before the environment can be read
+        if matches.occurrences_of("environment") > 0 {
+            let env_file = matches.value_of("environment").map(String::from);
+            config
+                .env_file
+                .merge(env_file, &Layer::Flag(String::from("from-env")));
+        }
+        dotenv::from_filename(&config.env_file.value).ok();
+
+        config.merge_env(&env::vars().collect())?;
+        config.merge_flags(matches)?;
+
+        if config.record.value.is_some() && config.replay.value.is_some() {
+            return Err(ReddSaverError::InvalidSetting(
+                String::from("replay"),
+                String::from("cannot be combined with record"),
+            ));
+        }
+
+        Ok(config)
+    }
+
+    /// The built-in defaults of every setting
+    fn defaults() -> Self {
+        Config {
+            config_file: None,
+            profile: None,
+            env_file: Setting::new(String::from(DEFAULT_ENV_FILE)),
+            data_directory: Setting::new(String::from(DEFAULT_DATA_DIR)),
+            dry_run: Setting::new(false),
+            human_readable: Setting::new(false),
+            subreddits: Setting::new(None),
+            unsave: Setting::new(false),
+            concurrent: Setting::new(false),
+            redirect_uri: Setting::new(String::from(DEFAULT_REDIRECT_URI)),
+            user_agent: Setting::new(None),
+            connect_timeout: Setting::new(DEFAULT_CONNECT_TIMEOUT),
+            read_timeout: Setting::new(DEFAULT_READ_TIMEOUT),
+            proxy: Setting::new(None),
+            ca_certs: Setting::new(Vec::new()),
+            pool_max_idle_per_host: Setting::new(DEFAULT_POOL_MAX_IDLE),
+            max_downloads: Setting::new(DEFAULT_MAX_DOWNLOADS),
+            max_per_host: Setting::new(DEFAULT_MAX_PER_HOST),
+            host_limits: Setting::new(HashMap::new()),
+            retry_attempts: Setting::new(DEFAULT_RETRY_ATTEMPTS),
+            retry_delay: Setting::new(DEFAULT_RETRY_DELAY),
+            video_quality: Setting::new(VideoQuality::Best),
+            muxer: Setting::new(Muxer::Builtin),
+            reddit_url: Setting::new(String::from(endpoints::DEFAULT_REDDIT_URL)),
+            oauth_url: Setting::new(String::from(endpoints::DEFAULT_OAUTH_URL)),
+            reddit_media_url: Setting::new(String::from(endpoints::DEFAULT_REDDIT_MEDIA_URL)),
+            gfycat_api_url: Setting::new(String::from(endpoints::DEFAULT_GFYCAT_API_URL)),
+            redgifs_api_url: Setting::new(String::from(endpoints::DEFAULT_REDGIFS_API_URL)),
+            giphy_media_url: Setting::new(String::from(endpoints::DEFAULT_GIPHY_MEDIA_URL)),
+            record: Setting::new(None),
+            replay: Setting::new(None),
+            accounts: Setting::new(Vec::new()),
+        }
+    }
+
+    /// Merge the top level of the configuration file at `path`, and then the profile named
+    /// `profile` if one is selected
+    fn merge_config_file(
+        &mut self,
+        path: &str,
+        mut file: ConfigFile,
+        profile: Option<&str>,
+    ) -> Result<(), ReddSaverError> {
+        self.merge_file(file.settings, &Layer::File(String::from(path)));
+
+        if let Some(name) = profile {
+            let settings = file
+                .profiles
+                .remove(name)
+                .ok_or_else(|| ReddSaverError::ProfileNotFound(String::from(name)))?;
+            self.merge_file(settings, &Layer::Profile(String::from(name)));
+            self.profile = Some(String::from(name));
+        }
+
+        Ok(())
+    }
+
+    fn merge_file(&mut self, settings: FileSettings, layer: &Layer) {
+        self.env_file.merge(settings.env_file, layer);
+        self.data_directory.merge(settings.data_dir, layer);
+        self.dry_run.merge(settings.dry_run, layer);
+        self.human_readable.merge(settings.human_readable, layer);
+        self.subreddits.merge(settings.subreddits.map(Some), layer);
+        self.unsave.merge(settings.unsave, layer);
+        self.concurrent.merge(settings.concurrent, layer);
+        self.redirect_uri.merge(settings.redirect_uri, layer);
+        self.user_agent.merge(settings.user_agent.map(Some), layer);
+        self.connect_timeout.merge(settings.connect_timeout, layer);
+        self.read_timeout.merge(settings.read_timeout, layer);
+        self.proxy.merge(settings.proxy.map(Some), layer);
+        self.ca_certs.merge(settings.ca_certs, layer);
+        self.pool_max_idle_per_host
+            .merge(settings.pool_max_idle_per_host, layer);
+        self.max_downloads.merge(settings.max_downloads, layer);
+        self.max_per_host.merge(settings.max_per_host, layer);
+        self.host_limits.merge(settings.host_limits, layer);
+        self.retry_attempts.merge(settings.retry_attempts, layer);
+        self.retry_delay.merge(settings.retry_delay, layer);
+        self.video_quality.merge(settings.video_quality, layer);
+        self.muxer.merge(settings.muxer, layer);
+        self.reddit_url.merge(settings.reddit_url, layer);
+        self.oauth_url.merge(settings.oauth_url, layer);
+        self.reddit_media_url
+            .merge(settings.reddit_media_url, layer);
+        self.gfycat_api_url.merge(settings.gfycat_api_url, layer);
+        self.redgifs_api_url.merge(settings.redgifs_api_url, layer);
+        self.giphy_media_url.merge(settings.giphy_media_url, layer);
+        self.record.merge(settings.record.map(Some), layer);
+        self.replay.merge(settings.replay.map(Some), layer);
+        self.accounts.merge(settings.accounts, layer);
+    }
+
+    /// Merge the settings found in `vars`, the variables of the environment
+    fn merge_env(&mut self, vars: &HashMap<String, String>) -> Result<(), ReddSaverError> {
+        let string = |name: &str| vars.get(name).cloned();
+        let layer = |name: &str| Layer::Env(String::from(name));
+
+        self.data_directory
+            .merge(string("RS_DATA_DIR"), &layer("RS_DATA_DIR"));
+        self.dry_run
+            .merge(env_flag(vars, "RS_DRY_RUN")?, &layer("RS_DRY_RUN"));
+        self.human_readable.merge(
+            env_flag(vars, "RS_HUMAN_READABLE")?,
+            &layer("RS_HUMAN_READABLE"),
+        );
+        self.subreddits.merge(
+            string("RS_SUBREDDITS").map(|s| Some(s.split(',').map(String::from).collect())),
+            &layer("RS_SUBREDDITS"),
+        );
+        self.unsave
+            .merge(env_flag(vars, "RS_UNSAVE")?, &layer("RS_UNSAVE"));
+        self.concurrent
+            .merge(env_flag(vars, "RS_CONCURRENT")?, &layer("RS_CONCURRENT"));
+        self.redirect_uri
+            .merge(string("REDIRECT_URI"), &layer("REDIRECT_URI"));
+        self.user_agent
+            .merge(string("RS_USER_AGENT").map(Some), &layer("RS_USER_AGENT"));
+        self.connect_timeout.merge(
+            env_setting(vars, "RS_CONNECT_TIMEOUT")?,
+            &layer("RS_CONNECT_TIMEOUT"),
+        );
+        self.read_timeout.merge(
+            env_setting(vars, "RS_READ_TIMEOUT")?,
+            &layer("RS_READ_TIMEOUT"),
+        );
+        self.proxy
+            .merge(string("RS_PROXY").map(Some), &layer("RS_PROXY"));
+        self.ca_certs.merge(
+            string("RS_CA_CERTS").map(|s| s.split(',').map(String::from).collect()),
+            &layer("RS_CA_CERTS"),
+        );
+        self.pool_max_idle_per_host.merge(
+            env_setting(vars, "RS_POOL_MAX_IDLE")?,
+            &layer("RS_POOL_MAX_IDLE"),
+        );
+        self.max_downloads.merge(
+            env_setting(vars, "RS_MAX_DOWNLOADS")?,
+            &layer("RS_MAX_DOWNLOADS"),
+        );
+        self.max_per_host.merge(
+            env_setting(vars, "RS_MAX_PER_HOST")?,
+            &layer("RS_MAX_PER_HOST"),
+        );
+        self.host_limits.merge(
+            parse_host_limits("RS_HOST_LIMITS", string("RS_HOST_LIMITS"))?,
+            &layer("RS_HOST_LIMITS"),
+        );
+        self.retry_attempts.merge(
+            env_setting(vars, "RS_RETRY_ATTEMPTS")?,
+            &layer("RS_RETRY_ATTEMPTS"),
+        );
+        self.retry_delay.merge(
+            env_setting(vars, "RS_RETRY_DELAY")?,
+            &layer("RS_RETRY_DELAY"),
+        );
+        self.video_quality.merge(
+            env_setting(vars, "RS_VIDEO_QUALITY")?,
+            &layer("RS_VIDEO_QUALITY"),
+        );
+        self.muxer
+            .merge(env_setting(vars, "RS_MUXER")?, &layer("RS_MUXER"));
+        self.reddit_url
+            .merge(string("RS_REDDIT_URL"), &layer("RS_REDDIT_URL"));
+        self.oauth_url
+            .merge(string("RS_OAUTH_URL"), &layer("RS_OAUTH_URL"));
+        self.reddit_media_url
+            .merge(string("RS_REDDIT_MEDIA_URL"), &layer("RS_REDDIT_MEDIA_URL"));
+        self.gfycat_api_url
+            .merge(string("RS_GFYCAT_API_URL"), &layer("RS_GFYCAT_API_URL"));
+        self.redgifs_api_url
+            .merge(string("RS_REDGIFS_API_URL"), &layer("RS_REDGIFS_API_URL"));
+        self.giphy_media_url
+            .merge(string("RS_GIPHY_MEDIA_URL"), &layer("RS_GIPHY_MEDIA_URL"));
+        self.record
+            .merge(string("RS_RECORD").map(Some), &layer("RS_RECORD"));
+        self.replay
+            .merge(string("RS_REPLAY").map(Some), &layer("RS_REPLAY"));
+
+        Ok(())
+    }
+
+    fn merge_flags(&mut self, matches: &ArgMatches) -> Result<(), ReddSaverError> {
+        // only flags given explicitly override the other layers, not the defaults shown in --help
+        let value = |name: &str| {
+            if matches.occurrences_of(name) > 0 {
+                matches.value_of(name).map(String::from)
+            } else {
+                None
+            }
+        };
+        let present = |name: &str| {
+            if matches.is_present(name) {
+                Some(true)
+            } else {
+                None
+            }
+        };
+        let layer = |flag: &str| Layer::Flag(String::from(flag));
+
+        self.data_directory
+            .merge(value("data_directory"), &layer("data-dir"));
+        self.dry_run.merge(present("dry_run"), &layer("dry-run"));
+        self.human_readable
+            .merge(present("human_readable"), &layer("human-readable"));
+        self.subreddits.merge(
+            matches
+                .values_of("subreddits")
+                .map(|v| Some(v.map(String::from).collect())),
+            &layer("subreddits"),
+        );
+        self.unsave.merge(present("unsave"), &layer("unsave"));
+        self.concurrent
+            .merge(present("concurrent"), &layer("concurrent"));
+        self.user_agent
+            .merge(value("user_agent").map(Some), &layer("user-agent"));
+        self.connect_timeout.merge(
+            parse_setting("--connect-timeout", value("connect_timeout"))?,
+            &layer("connect-timeout"),
+        );
+        self.read_timeout.merge(
+            parse_setting("--read-timeout", value("read_timeout"))?,
+            &layer("read-timeout"),
+        );
+        self.proxy.merge(value("proxy").map(Some), &layer("proxy"));
+        self.ca_certs.merge(
+            matches
+                .values_of("ca_certs")
+                .map(|v| v.map(String::from).collect()),
+            &layer("ca-cert"),
+        );
+        self.max_downloads.merge(
+            parse_setting("--max-downloads", value("max_downloads"))?,
+            &layer("max-downloads"),
+        );
+        self.max_per_host.merge(
+            parse_setting("--max-per-host", value("max_per_host"))?,
+            &layer("max-per-host"),
+        );
+        self.retry_attempts.merge(
+            parse_setting("--retry-attempts", value("retry_attempts"))?,
+            &layer("retry-attempts"),
+        );
+        self.retry_delay.merge(
+            parse_setting("--retry-delay", value("retry_delay"))?,
+            &layer("retry-delay"),
+        );
+        self.video_quality.merge(
+            parse_setting("--video-quality", value("video_quality"))?,
+            &layer("video-quality"),
+        );
+        self.muxer
+            .merge(parse_setting("--muxer", value("muxer"))?, &layer("muxer"));
+        self.record
+            .merge(value("record").map(Some), &layer("record"));
+        self.replay
+            .merge(value("replay").map(Some), &layer("replay"));
+
+        Ok(())
+    }
+
+    /// Settings used by accounts which do not set them on their own
+    pub fn account_defaults(&self) -> AccountDefaults {
+        AccountDefaults {
+            data_directory: self.data_directory.value.clone(),
+            subreddits: self.subreddits.value.clone(),
+            unsave: self.unsave.value,
+            user_agent: self.user_agent.value.clone(),
+        }
+    }
+
+    /// How Reddit videos are downloaded
+    pub fn video_settings(&self) -> VideoSettings {
+        VideoSettings {
+            quality: self.video_quality.value,
+            muxer: self.muxer.value,
+        }
+    }
+
+    /// Settings for the HTTP client shared by all accounts
+    pub fn http_settings(&self) -> HttpSettings {
+        HttpSettings {
+            connect_timeout: Duration::from_secs(self.connect_timeout.value),
+            read_timeout: Duration::from_secs(self.read_timeout.value),
+            proxy: self.proxy.value.clone(),
+            ca_certs: self.ca_certs.value.clone(),
+            pool_max_idle_per_host: self.pool_max_idle_per_host.value,
+            endpoints: self.endpoints(),
+            mode: match (&self.record.value, &self.replay.value) {
+                (_, Some(directory)) => Mode::Replay(PathBuf::from(directory)),
+                (Some(directory), None) => Mode::Record(PathBuf::from(directory)),
+                (None, None) => Mode::Live,
+            },
+            retry: RetryPolicy {
+                // a download is always attempted at least once
+                attempts: self.retry_attempts.value.max(1),
+                delay: Duration::from_millis(self.retry_delay.value),
+            },
+        }
+    }
+
+    /// Limits on the number of media downloaded at the same time, shared by all accounts
+    pub fn download_limits(&self) -> DownloadLimits {
+        DownloadLimits::new(
+            self.max_downloads.value,
+            self.max_per_host.value,
+            &self.host_limits.value,
+        )
+    }
+
+    /// Base URLs of the remote services to send requests to
+    pub fn endpoints(&self) -> Endpoints {
+        Endpoints {
+            reddit: self.reddit_url.value.clone(),
+            oauth: self.oauth_url.value.clone(),
+            reddit_media: self.reddit_media_url.value.clone(),
+            gfycat_api: self.gfycat_api_url.value.clone(),
+            redgifs_api: self.redgifs_api_url.value.clone(),
+            giphy_media: self.giphy_media_url.value.clone(),
+        }
+    }
+}
+
+/// Read a boolean environment variable such as `RS_UNSAVE=true`
+fn env_flag(vars: &HashMap<String, String>, name: &str) -> Result<Option<bool>, ReddSaverError> {
+    match vars.get(name).cloned() {
+        Some(v) => match v.to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(Some(true)),
+            "0" | "false" | "no" | "off" => Ok(Some(false)),
+            _ => Err(ReddSaverError::InvalidSetting(String::from(name), v)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Read an environment variable holding a value parsed with `FromStr`, such as
+/// `RS_READ_TIMEOUT=60` or `RS_MUXER=ffmpeg`
+fn env_setting<T: FromStr>(
+    vars: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<T>, ReddSaverError> {
+    parse_setting(name, vars.get(name).cloned())
+}
+
+/// Parse the value of the setting `name`, if it is set, reporting values that do not parse
+fn parse_setting<T: FromStr>(
+    name: &str,
+    value: Option<String>,
+) -> Result<Option<T>, ReddSaverError> {
+    match value {
+        Some(v) => match v.trim().parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(ReddSaverError::InvalidSetting(String::from(name), v)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parse per-host limits given as `imgur.com=4,redd.it=8`
+fn parse_host_limits(
+    name: &str,
+    value: Option<String>,
+) -> Result<Option<HashMap<String, usize>>, ReddSaverError> {
+    let value = match value {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let mut limits = HashMap::new();
+    for entry in value.split(',').filter(|e| !e.trim().is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let host = parts.next().unwrap_or_default().trim();
+        let limit = parts.next().and_then(|l| l.trim().parse::<usize>().ok());
+        match limit {
+            Some(l) if !host.is_empty() => limits.insert(String::from(host), l),
+            _ => {
+                return Err(ReddSaverError::InvalidSetting(
+                    String::from(name),
+                    value.clone(),
+                ))
+            }
+        };
+    }
+
+    Ok(Some(limits))
+}
+
+impl Account {
+    /// Build the account from `CLIENT_ID`, `CLIENT_SECRET`, `USERNAME`, etc. in the environment
+    pub fn from_env(
+        defaults: AccountDefaults,
+        password: Option<Secret>,
+    ) -> Result<Self, ReddSaverError> {
+        let username = env::var("USERNAME")?;
+        let password = match password {
+            Some(p) => Some(p),
+            None => SecretSpec::from_env("PASSWORD").resolve()?,
+        };
+
+        Ok(Self {
+            name: username.clone(),
+            user_agent: defaults
+                .user_agent
+                .unwrap_or_else(|| get_user_agent_string(&username)),
+            client_id: env::var("CLIENT_ID")?,
+            // secrets can also be read from a file (NAME_FILE) or the output of a command (NAME_COMMAND)
+            client_secret: SecretSpec::from_env("CLIENT_SECRET").require()?,
+            username,
+            // the password is optional once a refresh token has been stored with `reddsaver login`
+            password,
+            // base32 seed of accounts with 2FA, used to generate the one-time password on login
+            totp_secret: SecretSpec::from_env("TOTP_SECRET").resolve()?,
+            data_directory: defaults.data_directory,
+            subreddits: defaults.subreddits,
+            unsave: defaults.unsave,
+        })
+    }
+
+    /// Build the account from its entry in the configuration file
+    pub fn from_config(
+        config: &AccountConfig,
+        defaults: &AccountDefaults,
+    ) -> Result<Self, ReddSaverError> {
+        let config = config.clone();
+        let name = match config.name {
+            Some(n) => n,
+            None => config.username.clone(),
+        };
+        let user_agent = match config.user_agent.or_else(|| defaults.user_agent.clone()) {
+            Some(agent) => agent,
+            None => get_user_agent_string(&config.username),
+        };
+        let secret = |key: &str, value, file, command| {
+            SecretSpec::new(&format!("accounts.{}.{}", name, key), value, file, command)
+        };
+
+        Ok(Self {
+            client_id: config.client_id,
+            client_secret: secret(
+                "client_secret",
+                config.client_secret,
+                config.client_secret_file,
+                config.client_secret_command,
+            )
+            .require()?,
+            username: config.username,
+            password: secret(
+                "password",
+                config.password,
+                config.password_file,
+                config.password_command,
+            )
+            .resolve()?,
+            totp_secret: secret(
+                "totp_secret",
+                config.totp_secret,
+                config.totp_secret_file,
+                config.totp_secret_command,
+            )
+            .resolve()?,
+            data_directory: config
+                .data_dir
+                .unwrap_or_else(|| defaults.data_directory.clone()),
+            subreddits: config.subreddits.or_else(|| defaults.subreddits.clone()),
+            unsave: config.unsave.unwrap_or(defaults.unsave),
+            user_agent,
+            name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+
+    static CONFIG_FILE: &str = r#"
+unsave = true
+data_dir = "saved"
+subreddits = ["pics"]
+dry_run = true
+record = "responses"
+
+[profile.pics]
+data_dir = "pics"
+dry_run = false
+human_readable = true
+"#;
+
+    #[test]
+    fn later_layers_take_precedence() {
+        let mut config = Config::defaults();
+        let file = ConfigFile::parse(CONFIG_FILE).unwrap();
+        config
+            .merge_config_file("reddsaver.toml", file, Some("pics"))
+            .unwrap();
+
+        let vars = vec![("RS_DRY_RUN", "true"), ("RS_CONCURRENT", "true")]
+            .into_iter()
+            .map(|(name, value)| (String::from(name), String::from(value)))
+            .collect();
+        config.merge_env(&vars).unwrap();
+
+        let matches = App::new("reddsaver")
+            .arg(Arg::with_name("concurrent").long("concurrent"))
+            .arg(
+                Arg::with_name("data_directory")
+                    .long("data-dir")
+                    .takes_value(true),
+            )
+            .get_matches_from(vec!["reddsaver", "--data-dir", "flag"]);
+        config.merge_flags(&matches).unwrap();
+
+        let file = Layer::File(String::from("reddsaver.toml"));
+        let profile = Layer::Profile(String::from("pics"));
+        assert_eq!(
+            (
+                config.redirect_uri.value.as_str(),
+                &config.redirect_uri.layer
+            ),
+            (DEFAULT_REDIRECT_URI, &Layer::Default)
+        );
+        assert_eq!(
+            (config.human_readable.value, &config.human_readable.layer),
+            (true, &profile)
+        );
+        assert_eq!((config.unsave.value, &config.unsave.layer), (true, &file));
+        assert_eq!(config.record.value.as_deref(), Some("responses"));
+        assert_eq!(
+            (&config.subreddits.value, &config.subreddits.layer),
+            (&Some(vec![String::from("pics")]), &file)
+        );
+        assert_eq!(
+            (config.dry_run.value, &config.dry_run.layer),
+            (true, &Layer::Env(String::from("RS_DRY_RUN")))
+        );
+        assert_eq!(
+            (config.concurrent.value, &config.concurrent.layer),
+            (true, &Layer::Env(String::from("RS_CONCURRENT")))
+        );
+        assert_eq!(
+            (
+                config.data_directory.value.as_str(),
+                &config.data_directory.layer
+            ),
+            ("flag", &Layer::Flag(String::from("data-dir")))
+        );
+        assert_eq!(config.profile.as_deref(), Some("pics"));
+    }
+
+    #[test]
+    fn profiles_are_only_merged_when_selected() {
+        let mut config = Config::defaults();
+        let file = ConfigFile::parse(CONFIG_FILE).unwrap();
+        config
+            .merge_config_file("reddsaver.toml", file, None)
+            .unwrap();
+        assert_eq!(config.data_directory.value, "saved");
+        assert!(config.dry_run.value);
+        assert_eq!(config.profile, None);
+
+        let file = ConfigFile::parse(CONFIG_FILE).unwrap();
+        let missing = config.merge_config_file("reddsaver.toml", file, Some("gifs"));
+        assert!(matches!(missing, Err(ReddSaverError::ProfileNotFound(name)) if name == "gifs"));
+
+        assert!(ConfigFile::parse("unknown_key = 1").is_err());
+    }
+
+    #[test]
+    fn boolean_variables_accept_common_spellings() {
+        let flag = |value: &str| {
+            let mut vars = HashMap::new();
+            vars.insert(String::from("RS_TEST_ENV_FLAG"), String::from(value));
+            env_flag(&vars, "RS_TEST_ENV_FLAG")
+        };
+
+        assert_eq!(flag("yes").unwrap(), Some(true));
+        assert_eq!(flag("1").unwrap(), Some(true));
+        assert_eq!(flag("Off").unwrap(), Some(false));
+        assert!(flag("maybe").is_err());
+        assert_eq!(env_flag(&HashMap::new(), "RS_TEST_ENV_FLAG").unwrap(), None);
+    }
+
+    #[test]
+    fn host_limits_are_parsed() {
+        let limits = |value: &str| parse_host_limits("RS_HOST_LIMITS", Some(String::from(value)));
+
+        let mut expected = HashMap::new();
+        expected.insert(String::from("imgur.com"), 2);
+        expected.insert(String::from("redd.it"), 4);
+        assert_eq!(limits("imgur.com=2, redd.it=4,").unwrap(), Some(expected));
+        assert_eq!(limits("").unwrap(), Some(HashMap::new()));
+        assert!(limits("imgur.com").is_err());
+        assert!(limits("=2").is_err());
+        assert!(limits("imgur.com=many").is_err());
+        assert_eq!(parse_host_limits("RS_HOST_LIMITS", None).unwrap(), None);
+    }
+}
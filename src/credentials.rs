@@ -0,0 +1,44 @@
+use crate::errors::ReddSaverError;
+use crate::utils::write_private_file;
+
+use log::debug;
+use std::fs;
+use std::path::PathBuf;
+
+/// Directory under the user's configuration directory where reddsaver keeps its state
+static APP_DIRECTORY: &str = "reddsaver";
+
+/// Location of the refresh token stored by `reddsaver login` for the given user
+fn refresh_token_path(username: &str) -> Result<PathBuf, ReddSaverError> {
+    let mut path = dirs::config_dir().ok_or(ReddSaverError::ConfigDirNotFound)?;
+    path.push(APP_DIRECTORY);
+    path.push(format!("{}.refresh_token", username.to_lowercase()));
+
+    Ok(path)
+}
+
+/// Read the refresh token for the given user, if one has been stored
+pub fn load_refresh_token(username: &str) -> Result<Option<String>, ReddSaverError> {
+    let path = refresh_token_path(username)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    debug!("Reading refresh token from {}", path.display());
+    let token = fs::read_to_string(&path)?.trim().to_string();
+
+    Ok(if token.is_empty() { None } else { Some(token) })
+}
+
+/// Store the refresh token for the given user so that later runs can log in without a password
+pub fn save_refresh_token(username: &str, token: &str) -> Result<PathBuf, ReddSaverError> {
+    let path = refresh_token_path(username)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    write_private_file(&path, token.as_bytes())?;
+    debug!("Saved refresh token to {}", path.display());
+
+    Ok(path)
+}
@@ -1,21 +1,34 @@
 use std::borrow::Borrow;
-use std::fs::File;
+use std::collections::HashSet;
 use std::ops::Add;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::{fs, io};
+use std::time::SystemTime;
 
-use futures::stream::FuturesUnordered;
-use futures::TryStreamExt;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::future::join;
+use futures::{stream, SinkExt, StreamExt};
 use log::{debug, error, info, warn};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use url::{Position, Url};
 
+use crate::endpoints::Endpoints;
 use crate::errors::ReddSaverError;
-use crate::structures::{GfyData, PostData};
-use crate::structures::{Summary, UserSaved};
+use crate::failures::{Failure, FailureReport, DEFAULT_MAX_ATTEMPTS};
+use crate::http::Http;
+use crate::limits::DownloadLimits;
+use crate::manifest::{self, Representation, VideoQuality};
+use crate::mux::Muxer;
+use crate::partial::{self, part_path};
+use crate::retry::{error_for_status, is_dead, is_retryable};
+use crate::structures::{GalleryItem, GfyData, MediaMetadata, PostData, RedditVideo};
+use crate::structures::{Post, Summary};
 use crate::user::User;
-use crate::utils::check_path_present;
-use reqwest::StatusCode;
+use crate::validate::{self, MediaKind};
+use reqwest::header::{IF_RANGE, RANGE, USER_AGENT};
+use reqwest::{Response, StatusCode};
 
 static JPG_EXTENSION: &str = "jpg";
 static PNG_EXTENSION: &str = "png";
@@ -27,15 +40,15 @@ static REDDIT_DOMAIN: &str = "reddit.com";
 static REDDIT_IMAGE_SUBDOMAIN: &str = "i.redd.it";
 static REDDIT_VIDEO_SUBDOMAIN: &str = "v.redd.it";
 static REDDIT_GALLERY_PATH: &str = "gallery";
+/// Status of gallery items that Reddit could not process, which can never be downloaded
+static FAILED_UPLOAD_STATUS: &str = "failed";
 
 static IMGUR_DOMAIN: &str = "imgur.com";
 static IMGUR_SUBDOMAIN: &str = "i.imgur.com";
 
 static GFYCAT_DOMAIN: &str = "gfycat.com";
-static GFYCAT_API_PREFIX: &str = "https://api.gfycat.com/v1/gfycats";
 
 static REDGIFS_DOMAIN: &str = "redgifs.com";
-static REDGIFS_API_PREFIX: &str = "https://api.redgifs.com/v1/gfycats";
 
 static GIPHY_DOMAIN: &str = "giphy.com";
 static GIPHY_MEDIA_SUBDOMAIN: &str = "media.giphy.com";
@@ -55,193 +68,547 @@ enum MediaStatus {
     Skipped,
 }
 
+/// Size of the buffer media are written to disk through
+static WRITE_BUFFER_SIZE: usize = 64 * 1024;
+/// Number of bytes at the start of a media file looked at to recognize its format
+static SNIFF_LENGTH: usize = 16;
+/// Number of saved posts buffered between the listing fetcher and the download workers,
+/// one page of the listing
+static POST_BUFFER: usize = 100;
+/// Number of saved posts processed at the same time. Downloads are limited separately
+static CONCURRENT_POSTS: usize = 100;
+
 #[derive(Debug)]
 pub struct Downloader<'a> {
     user: &'a User<'a>,
-    saved: &'a Vec<UserSaved>,
+    limits: &'a DownloadLimits,
     data_directory: &'a str,
-    subreddits: &'a Option<Vec<&'a str>>,
+    subreddits: &'a Option<Vec<String>>,
     should_download: bool,
     use_human_readable: bool,
     unsave: bool,
+    /// Names and subreddits of the posts whose media have all been saved, which are unsaved
+    /// once the listing has been paged through
+    to_unsave: Mutex<Vec<(String, String)>>,
+    /// File names without extension of the media handled so far
+    handled: Mutex<HashSet<PathBuf>>,
+    /// Posts and media that could not be processed so far
+    failures: Mutex<Vec<Failure>>,
+    /// Names of the posts processed so far
+    processed: Mutex<HashSet<String>>,
+    video: VideoSettings,
+}
+
+/// How Reddit videos are downloaded
+#[derive(Debug, Clone, Copy)]
+pub struct VideoSettings {
+    /// Highest quality to pick from the manifests of a video
+    pub quality: VideoQuality,
+    /// Merges the separate video and audio tracks
+    pub muxer: Muxer,
+}
+
+/// A media of a post and where to download it from
+#[derive(Debug, Clone, PartialEq)]
+struct Media {
+    url: String,
+    /// What file names are hashed from instead of the URL, for media whose URL depends on
+    /// the quality picked. Gallery items are named after their post, and this is the URL
+    /// earlier versions named them after instead
+    key: Option<String>,
+    /// Audio track of a video that is served separately from it, and merged into it once both
+    /// have been downloaded
+    audio: Option<String>,
+    /// Position of the media in its gallery, starting at 1
+    position: Option<usize>,
+    /// Caption and outbound link of a gallery item, saved in a text file next to it
+    description: Option<String>,
+    /// Why the media cannot be downloaded, when Reddit already tells so
+    unavailable: Option<String>,
+}
+
+impl From<String> for Media {
+    fn from(url: String) -> Self {
+        Self {
+            url,
+            key: None,
+            audio: None,
+            position: None,
+            description: None,
+            unavailable: None,
+        }
+    }
 }
 
 impl<'a> Downloader<'a> {
     pub fn new(
         user: &'a User,
-        saved: &'a Vec<UserSaved>,
+        limits: &'a DownloadLimits,
         data_directory: &'a str,
-        subreddits: &'a Option<Vec<&'a str>>,
+        subreddits: &'a Option<Vec<String>>,
         should_download: bool,
         use_human_readable: bool,
         unsave: bool,
     ) -> Downloader<'a> {
         Downloader {
             user,
-            saved,
+            limits,
             data_directory,
             subreddits,
             should_download,
             use_human_readable,
             unsave,
+            to_unsave: Mutex::new(Vec::new()),
+            handled: Mutex::new(HashSet::new()),
+            failures: Mutex::new(Vec::new()),
+            processed: Mutex::new(HashSet::new()),
+            video: VideoSettings {
+                quality: VideoQuality::Best,
+                muxer: Muxer::Builtin,
+            },
         }
     }
 
-    pub async fn run(self) -> Result<(), ReddSaverError> {
-        let mut full_summary = Summary {
-            media_downloaded: 0,
-            media_skipped: 0,
-            media_supported: 0,
+    /// Pick the quality of Reddit videos and merge their video and audio tracks as given
+    pub fn video(mut self, video: VideoSettings) -> Self {
+        self.video = video;
+        self
+    }
+
+    /// Download the media of all saved posts. Posts are handed to the download workers as
+    /// soon as their page of the listing arrives, while the next page is being fetched
+    pub async fn run(self) -> Result<Summary, ReddSaverError> {
+        let started = SystemTime::now();
+        let summary = Arc::new(Mutex::new(Summary::default()));
+        // the bounded channel holds back the listing fetcher when the workers fall behind
+        let (sender, receiver) = mpsc::channel(POST_BUFFER);
+        let downloader = &self;
+
+        // a page that cannot be fetched ends the listing, while the workers finish the posts
+        // handed to them so far
+        let fetch = self
+            .user
+            .saved()
+            .forward(sender.sink_map_err(|_| ReddSaverError::DownloadAborted));
+        let download = receiver.for_each_concurrent(CONCURRENT_POSTS, |post| {
+            let summary_arc = summary.clone();
+            async move {
+                let post_summary = downloader.process_post(post).await;
+                let mut summary = summary_arc.lock().unwrap();
+                *summary = summary.add(post_summary);
+            }
+        });
+
+        let (listed, _) = join(fetch, download).await;
+        // unsaving a post while the listing is paged through would shift the pages after it
+        self.unsave_processed().await;
+
+        // every media of this run has been saved or given up on by now, so any of their partial
+        // downloads that was not written to during the run belongs to an interrupted one
+        if self.should_download {
+            let handled = self.handled.lock().unwrap().clone();
+            let removed = partial::remove_stale(self.data_directory, started, handled).await?;
+            if removed > 0 {
+                info!("Removed {} partial download(s) of earlier runs", removed);
+            }
+        }
+
+        // earlier failures of posts that were not processed this time, e.g. because they are
+        // in other subreddits, are kept as they are
+        let previous = FailureReport::load(&self.report_path(), self.user.name())?;
+        let processed = self.processed.lock().unwrap().clone();
+        let not_attempted = previous
+            .failures
+            .iter()
+            .filter(|f| !processed.contains(&f.post))
+            .cloned()
+            .collect();
+        // what failed so far is reported even when the listing could not be paged through
+        self.report_failures(&previous, not_attempted, DEFAULT_MAX_ATTEMPTS)?;
+        listed?;
+
+        let full_summary = *summary.lock().unwrap();
+        Ok(full_summary)
+    }
+
+    /// Make another attempt at the posts and media that failed in earlier runs, without going
+    /// through the saved listing. Those that fail `max_attempts` times are marked dead and
+    /// not attempted anymore
+    pub async fn retry(self, max_attempts: u32) -> Result<Summary, ReddSaverError> {
+        let previous = FailureReport::load(&self.report_path(), self.user.name())?;
+        let (dead, pending): (Vec<Failure>, Vec<Failure>) =
+            previous.failures.iter().cloned().partition(|f| f.dead);
+        if pending.is_empty() {
+            info!("Nothing to retry for {}", self.user.name());
+            return Ok(Summary::default());
+        }
+        info!("Retrying {} failed post(s) and media", pending.len());
+
+        // posts that failed as a whole are processed from scratch, which covers their media too.
+        // So are the posts of media without a file name, e.g. in hand edited reports
+        let unnamed = |f: &Failure| match &f.file_name {
+            Some(name) => name.is_empty(),
+            None => true,
+        };
+        let mut posts: Vec<String> = Vec::new();
+        for failure in pending.iter().filter(|f| f.url.is_none() || unnamed(f)) {
+            if !posts.contains(&failure.post) {
+                posts.push(failure.post.clone());
+            }
+        }
+        let media: Vec<&Failure> = pending
+            .iter()
+            .filter(|f| f.url.is_some() && !posts.contains(&f.post))
+            .collect();
+
+        let summary = Mutex::new(Summary::default());
+        let add = |s: Summary| {
+            let mut summary = summary.lock().unwrap();
+            *summary = summary.add(s);
         };
+        let (downloader, add) = (&self, &add);
 
-        for collection in self.saved {
-            full_summary = full_summary.add(self.download_collection(collection).await?);
+        stream::iter(media.iter())
+            .for_each_concurrent(CONCURRENT_POSTS, |failure| async move {
+                let (url, file_name) = match (&failure.url, &failure.file_name) {
+                    (Some(url), Some(file_name)) => (url, file_name),
+                    _ => return,
+                };
+                let media = Media {
+                    audio: failure.audio_url.clone(),
+                    ..url.clone().into()
+                };
+                let s = downloader
+                    .save_media_of(&failure.post, &failure.subreddit, &media, file_name)
+                    .await;
+                add(s);
+            })
+            .await;
+        if self.unsave && self.should_download {
+            self.unsave_recovered(&media).await;
         }
 
-        info!("#####################################");
-        info!("Download Summary:");
-        info!(
-            "Number of supported media: {}",
-            full_summary.media_supported
-        );
-        info!(
-            "Number of media downloaded: {}",
-            full_summary.media_downloaded
-        );
-        info!("Number of media skipped: {}", full_summary.media_skipped);
-        info!("#####################################");
-        info!("FIN.");
+        for names in posts.chunks(POST_BUFFER) {
+            let found = self.user.posts(names).await?;
+            for name in names {
+                if !found.iter().any(|p| p.data.name == *name) {
+                    let e = ReddSaverError::PostNotFound(name.clone());
+                    error!("{}", e);
+                    let subreddit = pending.iter().find(|f| f.post == *name).unwrap();
+                    self.fail(Failure::post(name, &subreddit.subreddit, &e));
+                }
+            }
+            stream::iter(found)
+                .for_each_concurrent(CONCURRENT_POSTS, |post| async move {
+                    add(downloader.process_post(post).await);
+                })
+                .await;
+        }
+        self.unsave_processed().await;
 
-        Ok(())
+        self.report_failures(&previous, dead, max_attempts)?;
+
+        let full_summary = *summary.lock().unwrap();
+        Ok(full_summary)
     }
 
-    /// Download and save medias from Reddit in parallel
-    async fn download_collection(&self, collection: &UserSaved) -> Result<Summary, ReddSaverError> {
-        let summary = Arc::new(Mutex::new(Summary {
-            media_supported: 0,
-            media_downloaded: 0,
-            media_skipped: 0,
-        }));
-
-        collection
-            .data
-            .children
-            .clone()
-            .into_iter()
-            // filter out the posts where a URL is present
-            // not that this application cannot download URLs linked within the text of the post
-            .filter(|item| item.data.url.is_some())
-            .map(|item| {
-                let summary_arc = summary.clone();
-                // since the latency for downloading an media from the network is unpredictable
-                // we spawn a new async task for the each of the medias to be downloaded
-                async move {
-                    let subreddit = item.data.subreddit.borrow();
-                    let post_name = item.data.name.borrow();
-                    let post_title = match item.data.title.as_ref() {
-                        Some(t) => t,
-                        None => "",
-                    };
-
-                    let is_valid = if let Some(s) = self.subreddits.as_ref() {
-                        if s.contains(&subreddit) {
-                            true
-                        } else {
-                            false
-                        }
-                    } else {
-                        true
-                    };
-
-                    if is_valid {
-                        debug!("Subreddit VALID: {} present in {:#?}", subreddit, subreddit);
-
-                        let media = get_media(item.data.borrow()).await?;
-                        // every entry in this vector is valid media
-                        summary_arc.lock().unwrap().media_supported += media.len() as i32;
-
-                        for (index, url) in media.iter().enumerate() {
-                            let extension =
-                                String::from(url.split('.').last().unwrap_or("unknown"));
-                            let file_name = self.generate_file_name(
-                                &url,
-                                &subreddit,
-                                &extension,
-                                &post_name,
-                                &post_title,
-                                &index,
-                            );
-
-                            if self.should_download {
-                                let status = save_or_skip(url, &file_name);
-                                // update the summary statistics based on the status
-                                match status.await? {
-                                    MediaStatus::Downloaded => {
-                                        summary_arc.lock().unwrap().media_downloaded += 1;
-                                    }
-                                    MediaStatus::Skipped => {
-                                        summary_arc.lock().unwrap().media_skipped += 1
-                                    }
-                                }
-                            } else {
-                                info!("Media available at URL: {}", &url);
-                                summary_arc.lock().unwrap().media_skipped += 1;
-                            }
-                        }
-                    } else {
-                        debug!(
-                            "Subreddit INVALID!: {} NOT present in {:#?}",
-                            subreddit, self.subreddits
-                        );
-                    }
+    /// Unsave the posts that were kept saved because some of their media failed, now that
+    /// all of them have been downloaded
+    async fn unsave_recovered(&self, retried: &[&Failure]) {
+        let failed: Vec<String> = self
+            .failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| f.post.clone())
+            .collect();
+        let mut recovered: Vec<&Failure> = Vec::new();
+        for failure in retried {
+            if !failed.contains(&failure.post) && !recovered.iter().any(|r| r.post == failure.post)
+            {
+                recovered.push(failure);
+            }
+        }
 
-                    if self.unsave {
-                        self.user.unsave(post_name).await?;
-                    }
+        for failure in recovered {
+            if let Err(e) = self.user.unsave(&failure.post).await {
+                error!("Could not unsave post {}: {}", failure.post, e);
+                self.fail(Failure::post(&failure.post, &failure.subreddit, &e));
+            }
+        }
+    }
+
+    /// Unsave the posts whose media have all been saved by now
+    async fn unsave_processed(&self) {
+        let posts: Vec<(String, String)> = self.to_unsave.lock().unwrap().drain(..).collect();
+        for (post, subreddit) in posts {
+            if let Err(e) = self.user.unsave(&post).await {
+                error!("Could not unsave post {}: {}", post, e);
+                self.fail(Failure::post(&post, &subreddit, &e));
+            }
+        }
+    }
+
+    /// Download and save the media of a single saved post. Whatever goes wrong is recorded
+    /// as a failure of the post or its media, so that it does not affect any other post
+    async fn process_post(&self, item: Post) -> Summary {
+        let mut summary = Summary::default();
+        // not that this application cannot download URLs linked within the text of the post
+        if item.data.url.is_none() {
+            return summary;
+        }
+
+        let subreddit = item.data.subreddit.borrow();
+        let post_name = item.data.name.borrow();
+        let post_title = match item.data.title.as_ref() {
+            Some(t) => t,
+            None => "",
+        };
 
-                    Ok::<(), ReddSaverError>(())
+        let is_valid = if let Some(s) = self.subreddits.as_ref() {
+            s.iter().any(|name| name == subreddit)
+        } else {
+            true
+        };
+
+        if is_valid {
+            debug!("Subreddit VALID: {} present in {:#?}", subreddit, subreddit);
+            self.processed
+                .lock()
+                .unwrap()
+                .insert(String::from(post_name));
+
+            let media = match get_media(
+                item.data.borrow(),
+                self.user.http(),
+                self.user.user_agent(),
+                self.video.quality,
+            )
+            .await
+            {
+                Ok(media) => media,
+                Err(e) => {
+                    error!("Could not find the media of post {}: {}", post_name, e);
+                    self.fail(Failure::post(post_name, subreddit, &e));
+                    summary.media_failed += 1;
+                    return summary;
                 }
-            })
-            .collect::<FuturesUnordered<_>>()
-            .try_collect::<()>()
-            .await?;
+            };
+            // every entry in this vector is valid media
+            summary.media_supported += media.len() as i32;
 
-        let local_summary = *summary.lock().unwrap();
+            for (index, m) in media.iter().enumerate() {
+                // the extension may still change once we know what the media really is
+                let extension = validate::url_extension(&m.url);
+                let file_name = self.generate_file_name(
+                    m,
+                    &subreddit,
+                    extension.as_deref(),
+                    &post_name,
+                    &post_title,
+                    &index,
+                );
+                let saved = self
+                    .save_media_of(post_name, subreddit, m, &file_name)
+                    .await;
+                // the description is only left next to media that is there
+                if let Some(description) = &m.description {
+                    if saved.media_failed == 0 && saved.media_dead == 0 {
+                        self.save_description(&file_name, description).await;
+                    }
+                }
+                summary = summary.add(saved);
+            }
+        } else {
+            debug!(
+                "Subreddit INVALID!: {} NOT present in {:#?}",
+                subreddit, self.subreddits
+            );
+        }
 
-        debug!("Collection statistics: ");
-        debug!(
-            "Number of supported media: {}",
-            local_summary.media_supported
-        );
-        debug!(
-            "Number of media downloaded: {}",
-            local_summary.media_downloaded
+        // keep the post saved if any of its media failed, so that it is not lost
+        if self.unsave && summary.media_failed == 0 && summary.media_dead == 0 {
+            self.to_unsave
+                .lock()
+                .unwrap()
+                .push((String::from(post_name), String::from(subreddit)));
+        }
+
+        summary
+    }
+
+    /// Download and save a single media of a post, unless it has been saved before
+    async fn save_media_of(
+        &self,
+        post: &str,
+        subreddit: &str,
+        media: &Media,
+        file_name: &str,
+    ) -> Summary {
+        let url = media.url.as_str();
+        let failure = |e: &ReddSaverError| Failure {
+            audio_url: media.audio.clone(),
+            ..Failure::media(post, subreddit, url, file_name, e)
+        };
+        let mut summary = Summary::default();
+        if let Some(reason) = &media.unavailable {
+            let e = ReddSaverError::MediaGone(String::from(url), reason.clone());
+            warn!("Giving up on media from url {}: {}", url, e);
+            self.fail(failure(&e));
+            summary.media_dead += 1;
+            return summary;
+        }
+        if !self.should_download {
+            info!("Media available at URL: {}", &url);
+            summary.media_skipped += 1;
+            return summary;
+        }
+
+        self.handled
+            .lock()
+            .unwrap()
+            .insert(PathBuf::from(validate::media_stem(file_name)));
+        // held until the media has been saved
+        let _permit = self.limits.acquire(url).await;
+        let legacy_file_name = self.legacy_file_name(media, subreddit);
+        let status = save_or_skip(
+            media,
+            file_name,
+            legacy_file_name.as_deref(),
+            self.user.http(),
+            self.user.user_agent(),
+            self.video.muxer,
         );
-        debug!("Number of media skipped: {}", local_summary.media_skipped);
+        // update the summary statistics based on the status
+        match status.await {
+            Ok(MediaStatus::Downloaded) => {
+                summary.media_downloaded += 1;
+            }
+            Ok(MediaStatus::Skipped) => summary.media_skipped += 1,
+            Err(e) if is_dead(&e) => {
+                warn!("Giving up on media from url {}: {}", url, e);
+                self.fail(failure(&e));
+                summary.media_dead += 1;
+            }
+            Err(e) => {
+                error!(
+                    "Could not save media from url {} to {}: {}",
+                    url, file_name, e
+                );
+                self.fail(failure(&e));
+                summary.media_failed += 1;
+            }
+        }
+
+        summary
+    }
+
+    /// Save the caption and link of a gallery item in a text file named after it
+    async fn save_description(&self, file_name: &str, description: &str) {
+        if !self.should_download {
+            return;
+        }
+        let path = format!("{}.txt", validate::media_stem(file_name));
+        if let Some(directory) = Path::new(&path).parent() {
+            if let Err(e) = fs::create_dir_all(directory).await {
+                warn!("Could not save the caption of {}: {}", file_name, e);
+                return;
+            }
+        }
+        if let Err(e) = fs::write(&path, description).await {
+            warn!("Could not save the caption of {}: {}", file_name, e);
+        }
+    }
+
+    fn report_path(&self) -> PathBuf {
+        FailureReport::path(self.data_directory, self.user.name())
+    }
 
-        Ok(local_summary)
+    fn fail(&self, failure: Failure) {
+        self.failures.lock().unwrap().push(failure);
+    }
+
+    /// Write the failures of the run to the data directory, if media are downloaded at all,
+    /// keeping count of how often they failed according to the report of the previous run
+    fn report_failures(
+        &self,
+        previous: &FailureReport,
+        not_attempted: Vec<Failure>,
+        max_attempts: u32,
+    ) -> Result<(), ReddSaverError> {
+        let failures = self.failures.lock().unwrap().clone();
+        if !failures.is_empty() {
+            warn!("{} post(s) or media could not be processed", failures.len());
+        }
+        if !self.should_download {
+            return Ok(());
+        }
+
+        let path = self.report_path();
+        let (report, dead) = previous.update(failures, not_attempted, max_attempts);
+        report.save(&path)?;
+        if !report.failures.is_empty() {
+            info!("Failures were reported in {}", path.display());
+        }
+
+        // items given up on are moved out of the report, so that they are not retried
+        if !dead.is_empty() {
+            let path = FailureReport::dead_path(self.data_directory, self.user.name());
+            let mut log = FailureReport::load(&path, self.user.name())?;
+            warn!(
+                "{} post(s) or media were given up on and logged in {}",
+                dead.len(),
+                path.display()
+            );
+            log.add(dead);
+            log.save(&path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Name without extension earlier versions saved a gallery item under, before gallery
+    /// items were named after their post
+    fn legacy_file_name(&self, media: &Media, subreddit: &str) -> Option<String> {
+        match (&media.key, media.position) {
+            (Some(key), Some(_)) if !self.use_human_readable => Some(format!(
+                "{}/{}/img-{:x}",
+                self.data_directory,
+                subreddit,
+                md5::compute(key)
+            )),
+            _ => None,
+        }
     }
 
     /// Generate a file name in the right format that Reddsaver expects
     fn generate_file_name(
         &self,
-        url: &str,
+        media: &Media,
         subreddit: &str,
-        extension: &str,
+        extension: Option<&str>,
         name: &str,
         title: &str,
         index: &usize,
     ) -> String {
-        return if !self.use_human_readable {
+        let stem = if let (false, Some(position)) = (self.use_human_readable, media.position) {
+            // gallery items are named after their post and numbered in the order of the
+            // gallery, so that they are listed in that order as well
+            let hash = md5::compute(name);
+            format!(
+                "{}/{}/img-{:x}-{:02}",
+                self.data_directory, subreddit, hash, position
+            )
+        } else if !self.use_human_readable {
             // create a hash for the media using the URL the media is located at
             // this helps to make sure the media download always writes the same file
             // name irrespective of how many times it's run. If run more than once, the
             // media is overwritten by this method
-            let hash = md5::compute(url);
+            let hash = md5::compute(media.key.as_ref().unwrap_or(&media.url));
             format!(
                 // TODO: Fixme, use appropriate prefix
-                "{}/{}/img-{:x}.{}",
-                self.data_directory, subreddit, hash, extension
+                "{}/{}/img-{:x}",
+                self.data_directory, subreddit, hash
             )
         } else {
             let canonical_title: String = title
@@ -269,106 +636,489 @@ impl<'a> Downloader<'a> {
             };
 
             format!(
-                "{}/{}/{}_{}.{}",
-                self.data_directory, subreddit, canonical_title, canonical_name, extension
+                "{}/{}/{}_{}",
+                self.data_directory, subreddit, canonical_title, canonical_name
             )
         };
+
+        match extension {
+            Some(extension) => format!("{}.{}", stem, extension),
+            None => stem,
+        }
     }
 }
 
 /// Helper function that downloads and saves a single media from Reddit or Imgur
-async fn save_or_skip(url: &str, file_name: &str) -> Result<MediaStatus, ReddSaverError> {
-    if check_path_present(&file_name) {
-        debug!("Image from url {} already downloaded. Skipping...", url);
-        Ok(MediaStatus::Skipped)
-    } else {
-        let save_status = download_media(&file_name, &url).await?;
-        if save_status {
-            Ok(MediaStatus::Downloaded)
-        } else {
-            Ok(MediaStatus::Skipped)
+async fn save_or_skip(
+    media: &Media,
+    file_name: &str,
+    legacy_file_name: Option<&str>,
+    http: &Http,
+    user_agent: &str,
+    muxer: Muxer,
+) -> Result<MediaStatus, ReddSaverError> {
+    let saved = match find_saved(file_name).await {
+        Some(saved) => Some(saved),
+        None => match legacy_file_name {
+            Some(legacy) => find_saved(legacy).await,
+            None => None,
+        },
+    };
+    if let Some(saved) = saved {
+        debug!(
+            "Media from url {} already downloaded to {}. Skipping...",
+            media.url, saved
+        );
+        return Ok(MediaStatus::Skipped);
+    }
+
+    match &media.audio {
+        Some(audio) => {
+            download_with_audio(file_name, &media.url, audio, http, user_agent, muxer).await?
+        }
+        None => {
+            download_media(&file_name, &media.url, http, user_agent).await?;
+        }
+    }
+    Ok(MediaStatus::Downloaded)
+}
+
+/// Download the video and audio tracks of a video one after another and merge them into a
+/// single MP4 file. The tracks are kept until they are merged, so that a failed merge does
+/// not need them to be downloaded again
+async fn download_with_audio(
+    file_name: &str,
+    video_url: &str,
+    audio_url: &str,
+    http: &Http,
+    user_agent: &str,
+    muxer: Muxer,
+) -> Result<(), ReddSaverError> {
+    let stem = validate::media_stem(file_name);
+    let track = |name: &str| format!("{}.{}", stem, name);
+    let video = match find_saved(&track("video")).await {
+        Some(saved) => saved,
+        None => download_media(&track("video"), video_url, http, user_agent).await?,
+    };
+    let audio = match find_saved(&track("audio")).await {
+        Some(saved) => Some(saved),
+        None => match download_media(&track("audio"), audio_url, http, user_agent).await {
+            Ok(saved) => Some(saved),
+            // keep the video without sound rather than losing it as well
+            Err(e) if is_dead(&e) => {
+                warn!("Saving video {} without audio: {}", video_url, e);
+                None
+            }
+            Err(e) => return Err(e),
+        },
+    };
+
+    let output = format!("{}.{}", stem, MP4_EXTENSION);
+    let audio = match audio {
+        Some(audio) => audio,
+        None => {
+            fs::rename(&video, &output).await?;
+            return Ok(());
+        }
+    };
+    let part = part_path(&output);
+    if let Err(e) = muxer
+        .merge(Path::new(&video), Path::new(&audio), &part)
+        .await
+    {
+        if let Err(e) = fs::remove_file(&part).await {
+            debug!("Could not remove {}: {}", part.display(), e);
+        }
+        return Err(e);
+    }
+    partial::commit(File::open(&part).await?, &part, &output, &output).await?;
+    info!("Merged video and audio into {}", output);
+    for track in &[video, audio] {
+        if let Err(e) = fs::remove_file(track).await {
+            warn!("Could not remove {}: {}", track, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// File the media meant for `file_name` was saved to by an earlier run, if any. Its extension
+/// may differ from the one of `file_name`, as it is decided by what was downloaded
+async fn find_saved(file_name: &str) -> Option<String> {
+    let stem = validate::media_stem(file_name);
+    let candidates = vec![String::from(file_name), String::from(stem)]
+        .into_iter()
+        .chain(
+            validate::MEDIA_EXTENSIONS
+                .iter()
+                .map(|e| format!("{}.{}", stem, e)),
+        );
+    for candidate in candidates {
+        if fs::metadata(&candidate).await.is_ok() {
+            return Some(candidate);
         }
     }
+
+    None
 }
 
-/// Download media from the given url and save to data directory. Also create data directory if not present already
-async fn download_media(file_name: &str, url: &str) -> Result<bool, ReddSaverError> {
+/// Download media from the given url and save to data directory, returning the name it was saved
+/// under. Also create data directory if not present already
+async fn download_media(
+    file_name: &str,
+    url: &str,
+    http: &Http,
+    user_agent: &str,
+) -> Result<String, ReddSaverError> {
     // create directory if it does not already exist
     // the directory is created relative to the current working directory
-    let mut status = false;
-    let directory = Path::new(file_name).parent().unwrap();
-    match fs::create_dir_all(directory) {
+    let directory = Path::new(file_name)
+        .parent()
+        .ok_or(ReddSaverError::CouldNotCreateDirectory)?;
+    match fs::create_dir_all(directory).await {
         Ok(_) => (),
         Err(_e) => return Err(ReddSaverError::CouldNotCreateDirectory),
     }
 
-    let maybe_response = reqwest::get(url).await;
-    if let Ok(response) = maybe_response {
-        debug!("URL Response: {:#?}", response);
-        let maybe_data = response.bytes().await;
-        if let Ok(data) = maybe_data {
-            debug!("Bytes length of the data: {:#?}", data.len());
-            let maybe_output = File::create(&file_name);
-            match maybe_output {
-                Ok(mut output) => {
-                    debug!("Created a file: {}", file_name);
-                    match io::copy(&mut data.as_ref(), &mut output) {
-                        Ok(_) => {
-                            info!("Successfully saved media: {} from url {}", file_name, url);
-                            status = true;
-                        }
-                        Err(_e) => {
-                            error!("Could not save media from url {} to {}", url, file_name);
-                        }
-                    }
-                }
-                Err(_) => {
-                    warn!(
-                        "Could not create a file with the name: {}. Skipping",
-                        file_name
-                    );
-                }
-            }
+    // a retry resumes from whatever the failed attempt managed to save, if the host allows it
+    let what = format!("Download of {}", url);
+    let saved = http
+        .retry()
+        .run(&what, || save_media(file_name, url, http, user_agent))
+        .await?;
+    info!("Successfully saved media: {} from url {}", saved, url);
+
+    Ok(saved)
+}
+
+/// Make a single attempt at downloading the media at `url` to `file_name`, returning the name
+/// it was saved under with the extension of the format that was actually downloaded
+async fn save_media(
+    file_name: &str,
+    url: &str,
+    http: &Http,
+    user_agent: &str,
+) -> Result<String, ReddSaverError> {
+    // an earlier attempt may have left a partial download which can be resumed from where it stopped
+    let part = part_path(file_name);
+    let resume = match fs::metadata(&part).await {
+        Ok(m) if m.len() > 0 => partial::load_validator(file_name, url)
+            .await
+            .map(|v| (m.len(), v)),
+        _ => None,
+    };
+
+    let mut response = fetch_media(url, http, user_agent, resume.as_ref()).await?;
+    debug!("URL Response: {:#?}", response);
+    let mut offset = 0;
+    if let Some((length, validator)) = &resume {
+        if response.status() == StatusCode::PARTIAL_CONTENT
+            && partial::range_start(response.headers()) == Some(*length)
+            && validator.matches(response.headers())
+        {
+            info!("Resuming download of {} at byte {}", url, length);
+            offset = *length;
+        } else if response.status() != StatusCode::OK && !is_transient(&response) {
+            // the partial response cannot be used, so start over from the beginning
+            debug!("Could not resume download of {}, starting over", url);
+            response = fetch_media(url, http, user_agent, None).await?;
         }
+        // with 200 OK, the remote file changed and the server sent all of it instead
     }
+    let mut response = error_for_status(response)?;
 
-    Ok(status)
-}
+    // make sure the response is the media rather than an error page or a placeholder
+    let expected = validate::url_extension(url).and_then(|e| MediaKind::from_extension(&e));
+    validate::check_response(url, response.url(), response.headers(), expected)?;
+    let first = if offset == 0 {
+        let first = http.chunk(&mut response).await?;
+        validate::check_body(url, first.as_deref().unwrap_or_default(), expected)?;
+        first
+    } else {
+        None
+    };
 
-/// Convert Gfycat/Redgifs GIFs into mp4 URLs for download
-async fn gfy_to_mp4(url: &str) -> Result<Option<String>, ReddSaverError> {
-    let api_prefix = if url.contains(GFYCAT_DOMAIN) {
-        GFYCAT_API_PREFIX
+    // remember how to resume this download in case it gets interrupted
+    let validator = match &resume {
+        Some((_, validator)) if offset > 0 => Some(validator.clone()),
+        _ => partial::Validator::from_response(url, response.headers()),
+    };
+    match &validator {
+        Some(v) => partial::save_validator(file_name, v).await?,
+        None => partial::remove_validator(file_name).await,
+    }
+
+    // write to a partial file first, so that an interrupted download never ends up
+    // under the final name, which would make it look like it was already downloaded
+    let output = if offset > 0 {
+        OpenOptions::new().append(true).open(&part).await?
     } else {
-        REDGIFS_API_PREFIX
+        File::create(&part).await?
     };
+    debug!("Writing to file: {}", part.display());
+    let written = match write_body(http, &mut response, first, output).await {
+        Ok((length, output)) => {
+            debug!("Bytes length of the data: {:#?}", offset + length);
+            // the response may not be in the format the URL suggests, e.g. a PNG behind a .jpg
+            let start = read_start(&part).await?;
+            let saved = match validate::detect_extension(response.headers(), &start) {
+                Some(extension) => format!("{}.{}", validate::media_stem(file_name), extension),
+                None => String::from(file_name),
+            };
+            partial::commit(output, &part, file_name, &saved)
+                .await
+                .map(|_| saved)
+        }
+        Err(e) => Err(e),
+    };
+
+    if written.is_err() && validator.is_none() {
+        if let Err(e) = fs::remove_file(&part).await {
+            warn!("Could not remove incomplete file {}: {}", part.display(), e);
+        }
+    }
+
+    written
+}
+
+/// First bytes of a file, enough to recognize the format of the media it holds
+async fn read_start(path: &Path) -> Result<Vec<u8>, ReddSaverError> {
+    let mut start = Vec::with_capacity(SNIFF_LENGTH);
+    File::open(path)
+        .await?
+        .take(SNIFF_LENGTH as u64)
+        .read_to_end(&mut start)
+        .await?;
+
+    Ok(start)
+}
+
+/// Whether the response is an error the request may not run into again, e.g. an overloaded server
+fn is_transient(response: &Response) -> bool {
+    let status = response.status();
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Request the media at `url`. When resuming a partial download, only the rest of the file is
+/// requested, provided it has not changed since the download started
+async fn fetch_media(
+    url: &str,
+    http: &Http,
+    user_agent: &str,
+    resume: Option<&(u64, partial::Validator)>,
+) -> Result<Response, ReddSaverError> {
+    let mut request = http.get(url).header(USER_AGENT, user_agent);
+    if let Some((length, validator)) = resume {
+        request = request
+            .header(RANGE, format!("bytes={}-", length))
+            .header(IF_RANGE, validator.if_range());
+    }
+
+    http.send(request).await
+}
+
+/// Write the response body to the file as it arrives, so that only a small buffer is held in
+/// memory whatever the size of the media. The read timeout applies to every chunk rather than
+/// to the body as a whole so that large media can take as long as they need
+async fn write_body(
+    http: &Http,
+    response: &mut Response,
+    first: Option<Bytes>,
+    file: File,
+) -> Result<(u64, File), ReddSaverError> {
+    let mut output = BufWriter::with_capacity(WRITE_BUFFER_SIZE, file);
+    let mut length = 0;
+    // the first chunk may have been read already to check what the response holds
+    if let Some(chunk) = first {
+        output.write_all(&chunk).await?;
+        length += chunk.len() as u64;
+    }
+    let received = loop {
+        match http.chunk(response).await {
+            Ok(Some(chunk)) => {
+                output.write_all(&chunk).await?;
+                length += chunk.len() as u64;
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    // keep what was received before the connection failed, so that the download can be resumed
+    output.flush().await?;
+    received?;
+
+    Ok((length, output.into_inner()))
+}
+
+/// Convert Gfycat/Redgifs GIFs into mp4 URLs for download
+async fn gfy_to_mp4(
+    url: &str,
+    http: &Http,
+    user_agent: &str,
+) -> Result<Option<String>, ReddSaverError> {
     let maybe_media_id = url.split("/").last();
 
     if let Some(media_id) = maybe_media_id {
-        let api_url = format!("{}/{}", api_prefix, media_id);
+        let api_url = if url.contains(GFYCAT_DOMAIN) {
+            http.endpoints().gfycat(media_id)
+        } else {
+            http.endpoints().redgifs(media_id)
+        };
         debug!("GFY API URL: {}", api_url);
-        let client = reqwest::Client::new();
-
         // talk to gfycat API and get GIF information
-        let response = client.get(&api_url).send().await?;
-        // if the gif is not available anymore, Gfycat might send
-        // a 404 response. Proceed to get the mp4 URL only if the
-        // response was HTTP 200
-        if response.status() == StatusCode::OK {
-            let data = response.json::<GfyData>().await?;
-            Ok(Some(data.gfy_item.mp4_url))
-        } else {
-            Ok(None)
+        let what = format!("Request to {}", api_url);
+        let api_url = api_url.as_str();
+        let response = http
+            .retry()
+            .run(&what, move || async move {
+                let request = http.get(api_url).header(USER_AGENT, user_agent);
+                error_for_status(http.send(request).await?)
+            })
+            .await;
+        match response {
+            Ok(response) => {
+                let data = response.json::<GfyData>().await?;
+                Ok(Some(data.gfy_item.mp4_url))
+            }
+            // if the gif is not available anymore, Gfycat might send a 404 response
+            Err(e @ ReddSaverError::HttpStatus(..)) if !is_retryable(&e) => {
+                warn!("Could not find media for {}: {}", url, e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
         }
     } else {
         Ok(None)
     }
 }
 
+/// Pick the video and audio of a Reddit video from its DASH manifest, or its HLS playlist
+/// when there is none, keeping the highest quality allowed. Without a usable manifest the
+/// fallback URL is used, which has no sound
+async fn reddit_video(
+    video: &RedditVideo,
+    http: &Http,
+    user_agent: &str,
+    quality: VideoQuality,
+) -> Result<Media, ReddSaverError> {
+    let mut representations = Vec::new();
+    if let Some(dash_url) = &video.dash_url {
+        representations = read_manifest(dash_url, http, user_agent, manifest::parse_dash).await;
+    }
+    if representations.is_empty() {
+        if let Some(hls_url) = &video.hls_url {
+            representations = read_manifest(hls_url, http, user_agent, manifest::parse_hls).await;
+        }
+    }
+    // streams split into segments cannot be saved as a single file
+    representations.retain(|r| !manifest::is_playlist(&r.url));
+
+    // the fallback URL is the same whatever the quality, so the video keeps its file name
+    let fallback = String::from(&video.fallback_url).replace("?source=fallback", "");
+    let url = match manifest::best_video(&representations, quality) {
+        Some(r) => r.url.clone(),
+        None => fallback.clone(),
+    };
+    // GIFs uploaded as video never have sound
+    let audio = if video.is_gif {
+        None
+    } else {
+        manifest::best_audio(&representations).map(|r| r.url.clone())
+    };
+
+    Ok(Media {
+        key: Some(fallback),
+        audio,
+        ..url.into()
+    })
+}
+
+/// Fetch a manifest and list its representations. Manifests that cannot be fetched or
+/// parsed are skipped with a warning, as the video is still found without them
+async fn read_manifest(
+    manifest_url: &str,
+    http: &Http,
+    user_agent: &str,
+    parse: fn(&str, &str) -> Result<Vec<Representation>, ReddSaverError>,
+) -> Vec<Representation> {
+    let what = format!("Request to {}", manifest_url);
+    let manifest = async {
+        let response = http
+            .retry()
+            .run(&what, move || async move {
+                let request = http.get(manifest_url).header(USER_AGENT, user_agent);
+                error_for_status(http.send(request).await?)
+            })
+            .await?;
+        parse(&response.text().await?, manifest_url)
+    };
+
+    match manifest.await {
+        Ok(representations) => representations,
+        Err(e) => {
+            warn!("Could not read the manifest {}: {}", manifest_url, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Media of the item at `index` in a gallery, found with the details Reddit gives of the
+/// upload. Without them, the item is taken to be a JPG image
+fn gallery_media(
+    item: &GalleryItem,
+    index: usize,
+    metadata: Option<&MediaMetadata>,
+    endpoints: &Endpoints,
+) -> Media {
+    let source = metadata.and_then(|m| m.source.as_ref());
+    let extension = metadata
+        .and_then(|m| m.mime_type.as_deref())
+        .and_then(validate::mime_extension);
+    // animated images are preferably saved as MP4, which is much smaller than the GIF
+    let animated = source.and_then(|s| s.mp4.as_ref().or(s.gif.as_ref()));
+    let url = match (animated, extension, source.and_then(|s| s.url.as_ref())) {
+        // the URLs of the sources have their ampersands escaped
+        (Some(url), _, _) => url.replace("&amp;", "&"),
+        (None, Some(extension), _) => endpoints.reddit_media(&item.media_id, extension),
+        (None, None, Some(url)) => url.replace("&amp;", "&"),
+        (None, None, None) => endpoints.reddit_media(&item.media_id, JPG_EXTENSION),
+    };
+
+    let description: Vec<&str> = item
+        .caption
+        .iter()
+        .chain(item.outbound_url.iter())
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    Media {
+        key: Some(endpoints.reddit_media(&item.media_id, JPG_EXTENSION)),
+        position: Some(index + 1),
+        description: if description.is_empty() {
+            None
+        } else {
+            Some(format!("{}\n", description.join("\n")))
+        },
+        unavailable: match metadata.and_then(|m| m.status.as_deref()) {
+            Some(status) if status == FAILED_UPLOAD_STATUS => {
+                Some(String::from("Reddit could not process the upload"))
+            }
+            _ => None,
+        },
+        ..url.into()
+    }
+}
+
 /// Check if a particular URL contains supported media.
-async fn get_media(data: &PostData) -> Result<Vec<String>, ReddSaverError> {
+async fn get_media(
+    data: &PostData,
+    http: &Http,
+    user_agent: &str,
+    quality: VideoQuality,
+) -> Result<Vec<Media>, ReddSaverError> {
     let original = data.url.as_ref().unwrap();
-    let mut media: Vec<String> = Vec::new();
+    let mut media: Vec<Media> = Vec::new();
 
     if let Ok(u) = Url::parse(original) {
         let mut parsed = u.clone();
@@ -390,7 +1140,7 @@ async fn get_media(data: &PostData) -> Result<Vec<String>, ReddSaverError> {
                 || url.ends_with(GIF_EXTENSION)
             {
                 let translated = String::from(url);
-                media.push(translated);
+                media.push(translated.into());
             }
         }
 
@@ -400,16 +1150,14 @@ async fn get_media(data: &PostData) -> Result<Vec<String>, ReddSaverError> {
             // mp4, then we can use the URL as is.
             if url.ends_with(MP4_EXTENSION) {
                 let translated = String::from(url);
-                media.push(translated);
+                media.push(translated.into());
             } else {
                 // if the URL uses the reddit video subdomain, but the link does not
-                // point directly to the mp4, then use the fallback URL to get the
-                // appropriate link. The video quality might range from 96p to 720p
+                // point directly to the mp4, then pick the best video its manifests list
+                // that is within the video quality, or use the fallback URL
                 if let Some(m) = &data.media {
                     if let Some(v) = &m.reddit_video {
-                        let translated =
-                            String::from(&v.fallback_url).replace("?source=fallback", "");
-                        media.push(translated);
+                        media.push(reddit_video(v, http, user_agent, quality).await?);
                     }
                 }
             }
@@ -418,13 +1166,12 @@ async fn get_media(data: &PostData) -> Result<Vec<String>, ReddSaverError> {
         // reddit image galleries
         if url.contains(REDDIT_DOMAIN) && url.contains(REDDIT_GALLERY_PATH) {
             if let Some(gallery) = gallery_info {
-                for item in gallery.items.iter() {
-                    // extract the media ID from each gallery item and reconstruct the image URL
-                    let translated = format!(
-                        "https://{}/{}.{}",
-                        REDDIT_IMAGE_SUBDOMAIN, item.media_id, JPG_EXTENSION
-                    );
-                    media.push(translated);
+                for (index, item) in gallery.items.iter().enumerate() {
+                    let metadata = data
+                        .media_metadata
+                        .as_ref()
+                        .and_then(|m| m.get(&item.media_id));
+                    media.push(gallery_media(item, index, metadata, http.endpoints()));
                 }
             }
         }
@@ -434,14 +1181,14 @@ async fn get_media(data: &PostData) -> Result<Vec<String>, ReddSaverError> {
             // if the Gfycat/Redgifs URL points directly to the mp4, download as is
             if url.ends_with(MP4_EXTENSION) {
                 let translated = String::from(url);
-                media.push(translated);
+                media.push(translated.into());
             } else {
                 // if the provided link is a gfycat post link, use the gfycat API
                 // to get the URL. gfycat likes to use lowercase names in their posts
                 // but the ID for the GIF is Pascal-cased. The case-conversion info
                 // can only be obtained from the API at the moment
-                if let Some(mp4_url) = gfy_to_mp4(url).await? {
-                    media.push(mp4_url);
+                if let Some(mp4_url) = gfy_to_mp4(url, http, user_agent).await? {
+                    media.push(mp4_url.into());
                 }
             }
         }
@@ -463,16 +1210,15 @@ async fn get_media(data: &PostData) -> Result<Vec<String>, ReddSaverError> {
                     || url.ends_with(GIFV_EXTENSION)
                 {
                     let translated = String::from(url);
-                    media.push(translated);
+                    media.push(translated.into());
                 }
             } else {
                 // if the link points to the giphy post rather than the media link,
                 // use the scheme below to get the actual URL for the gif.
                 let path = &parsed[Position::AfterHost..Position::AfterPath];
                 let media_id = path.split("-").last().unwrap();
-                let translated =
-                    format!("https://{}/media/{}.gif", GIPHY_MEDIA_SUBDOMAIN, media_id);
-                media.push(translated);
+                let translated = http.endpoints().giphy_media(media_id);
+                media.push(translated.into());
             }
         }
 
@@ -483,13 +1229,13 @@ async fn get_media(data: &PostData) -> Result<Vec<String>, ReddSaverError> {
             if url.contains(IMGUR_SUBDOMAIN) && url.ends_with(GIFV_EXTENSION) {
                 // if the extension is gifv, then replace gifv->mp4 to get the video URL
                 let translated = url.replace(GIFV_EXTENSION, MP4_EXTENSION);
-                media.push(translated);
+                media.push(translated.into());
             }
             if url.contains(IMGUR_SUBDOMAIN)
                 && (url.ends_with(PNG_EXTENSION) || url.ends_with(JPG_EXTENSION))
             {
                 let translated = String::from(url);
-                media.push(translated);
+                media.push(translated.into());
             }
         }
     }
@@ -0,0 +1,76 @@
+/// Base URL of the Reddit website, used for logging in
+pub static DEFAULT_REDDIT_URL: &str = "https://www.reddit.com";
+/// Base URL of the Reddit API, all requests with a bearer token have to be made to it
+pub static DEFAULT_OAUTH_URL: &str = "https://oauth.reddit.com";
+/// Base URL the images of Reddit galleries are served from
+pub static DEFAULT_REDDIT_MEDIA_URL: &str = "https://i.redd.it";
+pub static DEFAULT_GFYCAT_API_URL: &str = "https://api.gfycat.com/v1/gfycats";
+pub static DEFAULT_REDGIFS_API_URL: &str = "https://api.redgifs.com/v1/gfycats";
+/// Base URL of the Giphy CDN, used when a post links to the Giphy page instead of the GIF
+pub static DEFAULT_GIPHY_MEDIA_URL: &str = "https://media.giphy.com";
+
+/// Base URLs of every remote service reddsaver talks to. They only need to be changed to
+/// point reddsaver at a stand-in server, e.g. when testing
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    pub reddit: String,
+    pub oauth: String,
+    pub reddit_media: String,
+    pub gfycat_api: String,
+    pub redgifs_api: String,
+    pub giphy_media: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            reddit: String::from(DEFAULT_REDDIT_URL),
+            oauth: String::from(DEFAULT_OAUTH_URL),
+            reddit_media: String::from(DEFAULT_REDDIT_MEDIA_URL),
+            gfycat_api: String::from(DEFAULT_GFYCAT_API_URL),
+            redgifs_api: String::from(DEFAULT_REDGIFS_API_URL),
+            giphy_media: String::from(DEFAULT_GIPHY_MEDIA_URL),
+        }
+    }
+}
+
+impl Endpoints {
+    /// Page the user is sent to for granting reddsaver access to their account
+    pub fn authorize(&self) -> String {
+        join(&self.reddit, "api/v1/authorize")
+    }
+
+    /// Endpoint handing out access tokens
+    pub fn access_token(&self) -> String {
+        join(&self.reddit, "api/v1/access_token")
+    }
+
+    /// Reddit API endpoint at `path`, e.g. `user/<name>/saved`
+    pub fn oauth(&self, path: &str) -> String {
+        join(&self.oauth, path)
+    }
+
+    /// Image of a Reddit gallery with the given media ID and extension
+    pub fn reddit_media(&self, media_id: &str, extension: &str) -> String {
+        join(&self.reddit_media, &format!("{}.{}", media_id, extension))
+    }
+
+    /// Gfycat API endpoint returning the details of a GIF
+    pub fn gfycat(&self, media_id: &str) -> String {
+        join(&self.gfycat_api, media_id)
+    }
+
+    /// Redgifs API endpoint returning the details of a GIF
+    pub fn redgifs(&self, media_id: &str) -> String {
+        join(&self.redgifs_api, media_id)
+    }
+
+    /// GIF with the given ID on the Giphy CDN
+    pub fn giphy_media(&self, media_id: &str) -> String {
+        join(&self.giphy_media, &format!("media/{}.gif", media_id))
+    }
+}
+
+fn join(base: &str, path: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), path)
+}
@@ -25,4 +25,56 @@ pub enum ReddSaverError {
     IoError(#[from] std::io::Error),
     #[error("Unable to parse URL")]
     UrlError(#[from] url::ParseError),
+    #[error("Could not log in to Reddit: {0}")]
+    LoginFailed(String),
+    #[error(
+        "No password or stored refresh token for `{0}`, set PASSWORD or run `reddsaver login`"
+    )]
+    MissingCredentials(String),
+    #[error("Authorization was not granted: {0}")]
+    AuthorizationDenied(String),
+    #[error("Could not find the configuration directory for this platform")]
+    ConfigDirNotFound,
+    #[error("TOTP_SECRET is not a valid base32 encoded seed")]
+    InvalidTotpSecret,
+    #[error("Missing secret `{0}`, provide it directly, through a file or through a command")]
+    MissingSecret(String),
+    #[error("Could not read secret `{0}` from its file or command")]
+    CouldNotReadSecret(String),
+    #[error("Could not read configuration file `{0}`")]
+    CouldNotReadConfig(String),
+    #[error("Invalid configuration file: {0}")]
+    InvalidConfig(#[from] toml::de::Error),
+    #[error("No profile named `{0}` in the configuration file")]
+    ProfileNotFound(String),
+    #[error("Invalid value for `{0}`: `{1}`")]
+    InvalidSetting(String, String),
+    #[error("Timed out waiting for the server to respond")]
+    TimedOut,
+    #[error("`{0}` responded with HTTP {1}")]
+    HttpStatus(String, u16, Option<std::time::Duration>),
+    #[error("Media at `{0}` is gone: {1}")]
+    MediaGone(String, String),
+    #[error("Invalid manifest `{0}`: {1}")]
+    InvalidManifest(String, String),
+    #[error("Could not merge the video and audio tracks: {0}")]
+    MuxFailed(String),
+    #[error("Reddit kept responding with HTTP 429 (too many requests)")]
+    RateLimited,
+    #[error("No recorded response for `{0}`")]
+    FixtureNotFound(String),
+    #[error("Recorded response `{0}` is invalid")]
+    InvalidFixture(String),
+    #[error("Could not read certificates from `{0}`")]
+    CouldNotReadCertificate(String),
+    #[error("Post `{0}` could not be found")]
+    PostNotFound(String),
+    #[error("Downloads were aborted")]
+    DownloadAborted,
+    #[error("No account named `{0}` in the configuration")]
+    AccountNotFound(String),
+    #[error("Processing failed for {0} account(s)")]
+    AccountsFailed(usize),
+    #[error("--password-stdin needs a single account, but {0} are configured")]
+    PasswordStdinAmbiguous(usize),
 }
@@ -0,0 +1,224 @@
+use crate::errors::ReddSaverError;
+use crate::retry::is_dead;
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Default number of runs a post or media may fail in before it is considered dead and no
+/// longer retried by `reddsaver retry`
+pub static DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// A saved post, or one of its media, that could not be processed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Failure {
+    /// Full name of the post, e.g. `t3_abc123`
+    pub post: String,
+    pub subreddit: String,
+    /// URL of the media that could not be downloaded, or none if the post as a whole failed
+    pub url: Option<String>,
+    /// File the media was going to be saved to
+    pub file_name: Option<String>,
+    /// URL of the audio track of a video, which is downloaded separately
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_url: Option<String>,
+    /// Why it failed
+    pub error: String,
+    /// Number of runs it has failed in so far
+    #[serde(default = "first_attempt")]
+    pub attempts: u32,
+    /// Whether it is gone or failed too often to be worth retrying
+    #[serde(default)]
+    pub dead: bool,
+}
+
+fn first_attempt() -> u32 {
+    1
+}
+
+impl Failure {
+    pub fn post(post: &str, subreddit: &str, error: &ReddSaverError) -> Self {
+        Self {
+            post: String::from(post),
+            subreddit: String::from(subreddit),
+            url: None,
+            file_name: None,
+            audio_url: None,
+            error: error.to_string(),
+            attempts: 1,
+            dead: is_dead(error),
+        }
+    }
+
+    pub fn media(
+        post: &str,
+        subreddit: &str,
+        url: &str,
+        file_name: &str,
+        error: &ReddSaverError,
+    ) -> Self {
+        Self {
+            url: Some(String::from(url)),
+            file_name: Some(String::from(file_name)),
+            ..Self::post(post, subreddit, error)
+        }
+    }
+
+    /// Whether both are failures of the same post or media
+    fn same_item(&self, other: &Failure) -> bool {
+        self.post == other.post && self.url == other.url
+    }
+}
+
+/// Everything that failed during the last run for an account, written as JSON to the data
+/// directory so that it can be inspected or acted upon by other tools
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FailureReport {
+    /// Username of the account the saved posts belong to
+    pub account: String,
+    pub failures: Vec<Failure>,
+}
+
+impl FailureReport {
+    /// Location of the report of an account, e.g. `data/failures-<username>.json`
+    pub fn path(data_directory: &str, username: &str) -> PathBuf {
+        Path::new(data_directory).join(format!("failures-{}.json", username))
+    }
+
+    /// Location of the log of the items of an account that were given up on, e.g.
+    /// `data/dead-<username>.json`. It has the same format as the report
+    pub fn dead_path(data_directory: &str, username: &str) -> PathBuf {
+        Path::new(data_directory).join(format!("dead-{}.json", username))
+    }
+
+    /// Read the report written by an earlier run, which is empty if nothing failed then
+    pub fn load(path: &Path, account: &str) -> Result<Self, ReddSaverError> {
+        let contents = match fs::read(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    account: String::from(account),
+                    failures: Vec::new(),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(serde_json::from_slice(&contents).map_err(io::Error::from)?)
+    }
+
+    /// Report of a run that made another attempt at the items of this report. Items that
+    /// failed again keep count of their attempts, and are marked dead after `max_attempts`.
+    /// Items which were not attempted again are carried over as they are. Dead items are
+    /// left out of the report and returned on their own
+    pub fn update(
+        &self,
+        failures: Vec<Failure>,
+        not_attempted: Vec<Failure>,
+        max_attempts: u32,
+    ) -> (Self, Vec<Failure>) {
+        let mut updated: Vec<Failure> = failures
+            .into_iter()
+            .map(|mut failure| {
+                if let Some(previous) = self.failures.iter().find(|f| f.same_item(&failure)) {
+                    failure.attempts = previous.attempts + 1;
+                }
+                failure.dead = failure.dead || failure.attempts >= max_attempts;
+                failure
+            })
+            .collect();
+        updated.extend(not_attempted);
+        let (dead, pending) = updated.into_iter().partition(|f| f.dead);
+
+        let report = Self {
+            account: self.account.clone(),
+            failures: pending,
+        };
+        (report, dead)
+    }
+
+    /// Add items that were given up on, replacing earlier entries of the same items
+    pub fn add(&mut self, failures: Vec<Failure>) {
+        for failure in failures {
+            self.failures.retain(|f| !f.same_item(&failure));
+            self.failures.push(failure);
+        }
+    }
+
+    /// Write the report, or remove the one of an earlier run if nothing failed this time
+    pub fn save(&self, path: &Path) -> Result<(), ReddSaverError> {
+        if self.failures.is_empty() {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = serde_json::to_vec_pretty(self).map_err(io::Error::from)?;
+        fs::write(path, contents)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(post: &str, url: Option<&str>) -> Failure {
+        let error = ReddSaverError::TimedOut;
+        match url {
+            Some(u) => Failure::media(post, "pics", u, "data/pics/a.jpg", &error),
+            None => Failure::post(post, "pics", &error),
+        }
+    }
+
+    #[test]
+    fn repeated_failures_are_counted_until_dead() {
+        let previous = FailureReport {
+            account: String::from("tester"),
+            failures: vec![
+                Failure {
+                    attempts: 2,
+                    ..failure("t3_a", Some("https://i.redd.it/a.jpg"))
+                },
+                failure("t3_a", None),
+                failure("t3_b", None),
+            ],
+        };
+
+        let (updated, dead) = previous.update(
+            vec![
+                failure("t3_a", Some("https://i.redd.it/a.jpg")),
+                failure("t3_c", None),
+            ],
+            vec![failure("t3_d", None)],
+            3,
+        );
+
+        let summary = |failures: &[Failure]| -> Vec<(String, bool, u32, bool)> {
+            failures
+                .iter()
+                .map(|f| (f.post.clone(), f.url.is_some(), f.attempts, f.dead))
+                .collect()
+        };
+        assert_eq!(
+            summary(&updated.failures),
+            vec![
+                (String::from("t3_c"), false, 1, false),
+                (String::from("t3_d"), false, 1, false)
+            ]
+        );
+        assert_eq!(summary(&dead), vec![(String::from("t3_a"), true, 3, true)]);
+
+        // dying again replaces the earlier entry in the log of dead items
+        let mut log = FailureReport::default();
+        log.add(dead.clone());
+        log.add(dead);
+        assert_eq!(log.failures.len(), 1);
+    }
+}
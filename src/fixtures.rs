@@ -0,0 +1,192 @@
+use crate::errors::ReddSaverError;
+
+use bytes::Bytes;
+use log::debug;
+use reqwest::header::{HeaderMap, SET_COOKIE};
+use reqwest::{Method, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Fields of JSON responses holding secrets, which are never written to a fixture
+static REDACTED_FIELDS: [&str; 2] = ["access_token", "refresh_token"];
+static REDACTED: &str = "REDACTED";
+
+/// What to do with the traffic of a run
+#[derive(Debug, Clone)]
+pub enum Mode {
+    /// Talk to the remote services and keep nothing
+    Live,
+    /// Talk to the remote services and save every response to the directory
+    Record(PathBuf),
+    /// Answer every request from the responses saved in the directory, without any network access
+    Replay(PathBuf),
+}
+
+/// A response as saved in the fixture directory. The body is kept next to it in a file of its own
+#[derive(Serialize, Deserialize, Debug)]
+struct Fixture {
+    method: String,
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+/// Directory of recorded responses. Every response is stored under a name derived from
+/// the request method and URL, numbered in the order the same request was made during
+/// the run, so that replaying hands them out in the same order
+#[derive(Debug)]
+pub struct Fixtures {
+    directory: PathBuf,
+    /// Whether responses are read from the directory rather than written to it
+    replay: bool,
+    /// Number of times every request has been made so far
+    seen: Mutex<HashMap<String, usize>>,
+}
+
+impl Fixtures {
+    /// Fixture directory to use in the given mode, if any
+    pub fn from_mode(mode: &Mode) -> Option<Self> {
+        match mode {
+            Mode::Live => None,
+            Mode::Record(directory) => Some(Self::new(directory, false)),
+            Mode::Replay(directory) => Some(Self::new(directory, true)),
+        }
+    }
+
+    fn new(directory: &Path, replay: bool) -> Self {
+        Self {
+            directory: directory.to_path_buf(),
+            replay,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.replay
+    }
+
+    /// Save the response to a request, with secrets redacted
+    pub fn record(
+        &self,
+        method: &Method,
+        url: &Url,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<(), ReddSaverError> {
+        let name = self.next_name(method, url);
+        let fixture = Fixture {
+            method: method.to_string(),
+            url: url.to_string(),
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter(|(name, _)| *name != SET_COOKIE)
+                .filter_map(|(name, value)| {
+                    value
+                        .to_str()
+                        .ok()
+                        .map(|v| (name.to_string(), String::from(v)))
+                })
+                .collect(),
+        };
+
+        let contents = serde_json::to_vec_pretty(&fixture)
+            .map_err(|_| ReddSaverError::InvalidFixture(name.clone()))?;
+        fs::create_dir_all(&self.directory)?;
+        fs::write(self.directory.join(format!("{}.json", name)), contents)?;
+        fs::write(self.directory.join(format!("{}.body", name)), redact(body))?;
+        debug!("Recorded {} {} as {}", method, url, name);
+
+        Ok(())
+    }
+
+    /// Rebuild the response recorded for the request
+    pub fn replay(&self, method: &Method, url: &Url) -> Result<Response, ReddSaverError> {
+        let name = self.next_name(method, url);
+        let missing = || ReddSaverError::FixtureNotFound(format!("{} {}", method, url));
+
+        let contents =
+            fs::read(self.directory.join(format!("{}.json", name))).map_err(|_| missing())?;
+        let fixture: Fixture = serde_json::from_slice(&contents)
+            .map_err(|_| ReddSaverError::InvalidFixture(name.clone()))?;
+        let body =
+            fs::read(self.directory.join(format!("{}.body", name))).map_err(|_| missing())?;
+        debug!("Replaying {} {} from {}", method, url, name);
+
+        let mut builder = http::Response::builder().status(fixture.status);
+        for (name, value) in &fixture.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let response = builder
+            .body(Bytes::from(body))
+            .map_err(|_| ReddSaverError::InvalidFixture(name))?;
+
+        Ok(Response::from(response))
+    }
+
+    /// Rebuild a response that has already been read, to hand it back after recording it
+    pub fn rebuild(status: StatusCode, headers: HeaderMap, body: Vec<u8>) -> Response {
+        let mut response = http::Response::new(Bytes::from(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+
+        Response::from(response)
+    }
+
+    /// Name of the files holding the response to the request, e.g.
+    /// `get-oauth.reddit.com-user-name-saved-1a2b3c4d-0`
+    fn next_name(&self, method: &Method, url: &Url) -> String {
+        let readable: String = format!(
+            "{}-{}{}",
+            method.as_str().to_lowercase(),
+            url.host_str().unwrap_or_default(),
+            url.path()
+        )
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .take(120)
+        .collect();
+        // the query has to be part of the name as well, e.g. for the pages of a listing
+        let hash = format!("{:x}", md5::compute(format!("{} {}", method, url)));
+        let key = format!("{}-{}", readable.trim_end_matches('-'), &hash[..8]);
+
+        let mut seen = self.seen.lock().unwrap();
+        let count = seen.entry(key.clone()).or_insert(0);
+        let name = format!("{}-{}", key, count);
+        *count += 1;
+
+        name
+    }
+}
+
+/// Replace the secrets in a JSON body, leaving anything else untouched
+fn redact(body: &[u8]) -> Vec<u8> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(mut object)) => {
+            let mut redacted = false;
+            for field in REDACTED_FIELDS.iter() {
+                if let Some(value) = object.get_mut(*field) {
+                    *value = Value::String(String::from(REDACTED));
+                    redacted = true;
+                }
+            }
+            if redacted {
+                serde_json::to_vec(&object).unwrap_or_default()
+            } else {
+                body.to_vec()
+            }
+        }
+        _ => body.to_vec(),
+    }
+}
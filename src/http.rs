@@ -0,0 +1,171 @@
+use crate::endpoints::Endpoints;
+use crate::errors::ReddSaverError;
+use crate::fixtures::{Fixtures, Mode};
+use crate::retry::RetryPolicy;
+
+use bytes::Bytes;
+use log::debug;
+use reqwest::{Certificate, Proxy, RequestBuilder, Response};
+use std::fs;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::timeout;
+
+static PEM_BEGIN_MARKER: &str = "-----BEGIN CERTIFICATE-----";
+static PEM_END_MARKER: &str = "-----END CERTIFICATE-----";
+
+/// Settings for the HTTP client shared by all requests
+#[derive(Debug, Clone)]
+pub struct HttpSettings {
+    /// Maximum time to wait for a connection to be established
+    pub connect_timeout: Duration,
+    /// Maximum time to wait for the server to send the next piece of data
+    pub read_timeout: Duration,
+    /// HTTP, HTTPS or SOCKS5 proxy to send all requests through
+    pub proxy: Option<String>,
+    /// PEM files with additional certificate authorities to trust
+    pub ca_certs: Vec<String>,
+    /// Maximum number of idle connections kept open per host
+    pub pool_max_idle_per_host: usize,
+    /// Base URLs of the remote services
+    pub endpoints: Endpoints,
+    /// Whether to record or replay the traffic
+    pub mode: Mode,
+    /// How to retry failed requests to the media hosts
+    pub retry: RetryPolicy,
+}
+
+/// HTTP client shared by the authentication, API and download code, so that connections
+/// are reused and the same timeouts and proxy apply everywhere
+#[derive(Debug)]
+pub struct Http {
+    client: reqwest::Client,
+    read_timeout: Duration,
+    endpoints: Endpoints,
+    retry: RetryPolicy,
+    /// Where responses are recorded to or replayed from, if not talking to the network only
+    fixtures: Option<Fixtures>,
+}
+
+impl Http {
+    pub fn new(settings: &HttpSettings) -> Result<Self, ReddSaverError> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(settings.connect_timeout)
+            .pool_max_idle_per_host(settings.pool_max_idle_per_host);
+
+        if let Some(proxy) = &settings.proxy {
+            debug!("Sending all requests through proxy {}", proxy);
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        for path in &settings.ca_certs {
+            for certificate in read_certificates(path)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            read_timeout: settings.read_timeout,
+            endpoints: settings.endpoints.clone(),
+            retry: settings.retry,
+            fixtures: Fixtures::from_mode(&settings.mode),
+        })
+    }
+
+    /// Base URLs of the remote services requests are sent to
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    /// How to retry failed requests to the media hosts
+    pub fn retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    /// Whether responses are recorded or replayed, so nothing should outlive the run
+    pub fn uses_fixtures(&self) -> bool {
+        self.fixtures.is_some()
+    }
+
+    /// Send the request and wait for the response headers, giving up after the read timeout.
+    /// When recording, the whole response is read and saved before being handed back. When
+    /// replaying, nothing is sent and the recorded response is returned instead
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, ReddSaverError> {
+        let fixtures = match &self.fixtures {
+            Some(f) => f,
+            None => return self.wait(request.send()).await,
+        };
+
+        let request = request.build()?;
+        let (method, url) = (request.method().clone(), request.url().clone());
+        if fixtures.is_replay() {
+            return fixtures.replay(&method, &url);
+        }
+
+        let mut response = self.wait(self.client.execute(request)).await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let mut body = Vec::new();
+        while let Some(chunk) = self.chunk(&mut response).await? {
+            body.extend_from_slice(&chunk);
+        }
+        fixtures.record(&method, &url, status, &headers, &body)?;
+
+        Ok(Fixtures::rebuild(status, headers, body))
+    }
+
+    /// Wait for the response headers, giving up after the read timeout
+    async fn wait<F>(&self, response: F) -> Result<Response, ReddSaverError>
+    where
+        F: Future<Output = reqwest::Result<Response>>,
+    {
+        match timeout(self.read_timeout, response).await {
+            Ok(response) => Ok(response?),
+            Err(_) => Err(ReddSaverError::TimedOut),
+        }
+    }
+
+    /// Read the next chunk of the response body, giving up after the read timeout
+    pub async fn chunk(&self, response: &mut Response) -> Result<Option<Bytes>, ReddSaverError> {
+        match timeout(self.read_timeout, response.chunk()).await {
+            Ok(chunk) => Ok(chunk?),
+            Err(_) => Err(ReddSaverError::TimedOut),
+        }
+    }
+}
+
+/// Read all certificates from a PEM file, which may contain a whole bundle of them
+fn read_certificates(path: &str) -> Result<Vec<Certificate>, ReddSaverError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|_| ReddSaverError::CouldNotReadCertificate(String::from(path)))?;
+
+    let mut certificates = Vec::new();
+    for block in contents.split(PEM_END_MARKER) {
+        if block.contains(PEM_BEGIN_MARKER) {
+            let pem = format!("{}{}", block.trim_start(), PEM_END_MARKER);
+            let certificate = Certificate::from_pem(pem.as_bytes())
+                .map_err(|_| ReddSaverError::CouldNotReadCertificate(String::from(path)))?;
+            certificates.push(certificate);
+        }
+    }
+
+    if certificates.is_empty() {
+        return Err(ReddSaverError::CouldNotReadCertificate(String::from(path)));
+    }
+    debug!(
+        "Trusting {} certificate(s) from {}",
+        certificates.len(),
+        path
+    );
+
+    Ok(certificates)
+}
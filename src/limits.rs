@@ -0,0 +1,121 @@
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+use url::Url;
+
+/// Default number of media downloaded at the same time, across all accounts
+pub static DEFAULT_MAX_DOWNLOADS: usize = 16;
+/// Default number of media downloaded at the same time from a host without a limit of its own
+pub static DEFAULT_MAX_PER_HOST: usize = 4;
+/// Built-in per-host limits. A limit applies to the host and all its subdomains
+static DEFAULT_HOST_LIMITS: [(&str, usize); 2] = [("imgur.com", 4), ("redd.it", 8)];
+
+/// Limits on the number of media downloaded at the same time, both overall and per host,
+/// shared by all accounts so that no host sees more connections than it allows
+#[derive(Debug)]
+pub struct DownloadLimits {
+    global: Semaphore,
+    /// Limit of every host with one of its own, e.g. `imgur.com`
+    host_limits: HashMap<String, usize>,
+    /// Limit of every other host
+    max_per_host: usize,
+    /// Semaphores of the hosts downloaded from so far, created on first use
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// Permission to download from a host, released when dropped
+#[derive(Debug)]
+pub struct DownloadPermit<'a> {
+    _global: SemaphorePermit<'a>,
+    _host: OwnedSemaphorePermit,
+}
+
+impl DownloadLimits {
+    /// Create the limits, with `host_limits` overriding or adding to the built-in ones
+    pub fn new(
+        max_downloads: usize,
+        max_per_host: usize,
+        host_limits: &HashMap<String, usize>,
+    ) -> Self {
+        let mut limits: HashMap<String, usize> = DEFAULT_HOST_LIMITS
+            .iter()
+            .map(|(host, limit)| (String::from(*host), *limit))
+            .collect();
+        for (host, limit) in host_limits {
+            limits.insert(host.to_lowercase(), *limit);
+        }
+
+        Self {
+            // a limit of 0 would block all downloads forever
+            global: Semaphore::new(max_downloads.max(1)),
+            host_limits: limits,
+            max_per_host: max_per_host.max(1),
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait until a download from the host of `url` is allowed
+    pub async fn acquire(&self, url: &str) -> DownloadPermit<'_> {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_lowercase))
+            .unwrap_or_default();
+        let (key, limit) = self.limit_of(&host);
+
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+        // take the host permit first, so that a busy host does not hold up the global ones
+        let host_permit = semaphore.acquire_owned().await;
+        let global_permit = self.global.acquire().await;
+        debug!("Acquired download permit for {}", host);
+
+        DownloadPermit {
+            _global: global_permit,
+            _host: host_permit,
+        }
+    }
+
+    /// The host or domain whose limit applies to `host`, along with the limit
+    fn limit_of(&self, host: &str) -> (String, usize) {
+        self.host_limits
+            .iter()
+            .filter(|(domain, _)| host == *domain || host.ends_with(&format!(".{}", domain)))
+            // prefer the most specific domain, e.g. i.imgur.com over imgur.com
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(domain, limit)| (domain.clone(), (*limit).max(1)))
+            .unwrap_or_else(|| (String::from(host), self.max_per_host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_domain_limit_applies() {
+        let mut overrides = HashMap::new();
+        overrides.insert(String::from("i.imgur.com"), 2);
+        let limits = DownloadLimits::new(16, 3, &overrides);
+
+        assert_eq!(
+            limits.limit_of("i.imgur.com"),
+            (String::from("i.imgur.com"), 2)
+        );
+        assert_eq!(limits.limit_of("imgur.com"), (String::from("imgur.com"), 4));
+        assert_eq!(limits.limit_of("v.redd.it"), (String::from("redd.it"), 8));
+        assert_eq!(
+            limits.limit_of("notredd.it"),
+            (String::from("notredd.it"), 3)
+        );
+        assert_eq!(
+            limits.limit_of("media.giphy.com"),
+            (String::from("media.giphy.com"), 3)
+        );
+    }
+}
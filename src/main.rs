@@ -1,23 +1,46 @@
-use std::env;
-
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, SubCommand};
 use env_logger::Env;
-use log::{debug, info};
+use futures::future::join_all;
+use log::{debug, error, info};
 
-use auth::Client;
+use auth::{Client, Session};
 
-use crate::download::Downloader;
+use crate::cache::TokenCache;
+use crate::config::{Account, Config, DEFAULT_DATA_DIR, DEFAULT_ENV_FILE};
+use crate::download::{Downloader, VideoSettings};
 use crate::errors::ReddSaverError;
 use crate::errors::ReddSaverError::DataDirNotFound;
+use crate::failures::DEFAULT_MAX_ATTEMPTS;
+use crate::http::Http;
+use crate::limits::DownloadLimits;
+use crate::structures::Summary;
+use crate::totp::Totp;
 use crate::user::User;
 use crate::utils::*;
 
 mod auth;
+mod cache;
+mod config;
+mod credentials;
 mod download;
+mod endpoints;
 mod errors;
+mod failures;
+mod fixtures;
+mod http;
+mod limits;
+mod manifest;
+mod mux;
+mod partial;
+mod ratelimit;
+mod redirect;
+mod retry;
+mod secrets;
 mod structures;
+mod totp;
 mod user;
 mod utils;
+mod validate;
 
 #[tokio::main]
 async fn main() -> Result<(), ReddSaverError> {
@@ -31,7 +54,23 @@ async fn main() -> Result<(), ReddSaverError> {
                 .long("from-env")
                 .value_name("ENV_FILE")
                 .help("Set a custom .env style file with secrets")
-                .default_value(".env")
+                .default_value(DEFAULT_ENV_FILE)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("CONFIG_FILE")
+                .help("Read settings from this TOML file instead of reddsaver.toml in the config directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .short("p")
+                .long("profile")
+                .value_name("PROFILE")
+                .help("Apply the settings of this profile from the configuration file")
                 .takes_value(true),
         )
         .arg(
@@ -40,7 +79,7 @@ async fn main() -> Result<(), ReddSaverError> {
                 .long("data-dir")
                 .value_name("DATA_DIR")
                 .help("Directory to save the media to")
-                .default_value("data")
+                .default_value(DEFAULT_DATA_DIR)
                 .takes_value(true),
         )
         .arg(
@@ -81,70 +120,462 @@ async fn main() -> Result<(), ReddSaverError> {
                 .takes_value(false)
                 .help("Unsave post after processing"),
         )
+        .arg(
+            Arg::with_name("user_agent")
+                .long("user-agent")
+                .value_name("USER_AGENT")
+                .help("Identify with this user agent instead of the one derived from the username")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect_timeout")
+                .long("connect-timeout")
+                .value_name("SECONDS")
+                .help("Give up connecting to a server after this many seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read_timeout")
+                .long("read-timeout")
+                .value_name("SECONDS")
+                .help("Give up on a request when the server sends nothing for this many seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .value_name("URL")
+                .help("Send all requests through this HTTP, HTTPS or SOCKS5 proxy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ca_certs")
+                .long("ca-cert")
+                .value_name("PEM_FILE")
+                .help("Also trust the certificate authorities in this PEM file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("max_downloads")
+                .long("max-downloads")
+                .value_name("COUNT")
+                .help("Download at most this many media at the same time")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_per_host")
+                .long("max-per-host")
+                .value_name("COUNT")
+                .help("Download at most this many media at the same time from a single host")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_attempts")
+                .long("retry-attempts")
+                .value_name("COUNT")
+                .help("Try downloading a media this many times before giving up on it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_delay")
+                .long("retry-delay")
+                .value_name("MILLISECONDS")
+                .help("Wait this long before the first retry, doubling it for every further one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("video_quality")
+                .long("video-quality")
+                .value_name("QUALITY")
+                .help("Highest quality of Reddit videos to download, `best` or a height like 720p")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("muxer")
+                .long("muxer")
+                .value_name("MUXER")
+                .help("Merge the video and audio of Reddit videos with the built-in muxer or ffmpeg")
+                .possible_values(&["builtin", "ffmpeg"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("DIR")
+                .help("Save every response to this directory, with secrets redacted")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("DIR")
+                .help("Answer every request from the responses recorded in this directory")
+                .takes_value(true)
+                .conflicts_with("record"),
+        )
+        .arg(
+            Arg::with_name("concurrent")
+                .long("concurrent")
+                .takes_value(false)
+                .help("Process all accounts at the same time"),
+        )
+        .arg(
+            Arg::with_name("password_stdin")
+                .long("password-stdin")
+                .takes_value(false)
+                .help("Read the Reddit password from the standard input"),
+        )
+        .subcommand(
+            SubCommand::with_name("login")
+                .about("Authorize reddsaver in the browser and store a refresh token")
+                .arg(
+                    Arg::with_name("account")
+                        .short("a")
+                        .long("account")
+                        .value_name("ACCOUNT")
+                        .help("Name of the account from the configuration file to log in to")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("retry")
+                .about("Try the posts and media that failed in earlier runs again")
+                .arg(
+                    Arg::with_name("max_attempts")
+                        .long("max-attempts")
+                        .value_name("COUNT")
+                        .help("Give up on a post or media after it failed this many times")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
-    let env_file = matches.value_of("environment").unwrap();
-    let data_directory = String::from(matches.value_of("data_directory").unwrap());
+    // merge the configuration file, the environment and the flags into the final configuration
+    let config = Config::load(&matches)?;
+
+    // initialize logger for the app and set logging level to info if no environment variable present
+    let env = Env::default().filter("RS_LOG").default_filter_or("info");
+    env_logger::Builder::from_env(env).init();
+
     // generate the URLs to download from without actually downloading the media
-    let should_download = !matches.is_present("dry_run");
+    let should_download = !config.dry_run.value;
     // generate human readable file names instead of MD5 Hashed file names
-    let use_human_readable = matches.is_present("human_readable");
-    // restrict downloads to these subreddits
-    let subreddits: Option<Vec<&str>> = if matches.is_present("subreddits") {
-        Some(matches.values_of("subreddits").unwrap().collect())
+    let use_human_readable = config.human_readable.value;
+    let redirect_uri = &config.redirect_uri.value;
+    // accounts without their own settings fall back to the global ones
+    let defaults = config.account_defaults();
+
+    // the password piped in can only belong to one account, so refuse to guess which
+    let configured = config.accounts.value.len();
+    let password = if matches.is_present("password_stdin") {
+        if configured > 1 {
+            return Err(ReddSaverError::PasswordStdinAmbiguous(configured));
+        }
+        Some(secrets::from_stdin()?)
     } else {
         None
     };
-    let unsave = matches.is_present("unsave");
 
-    // initialize environment from the .env file
-    dotenv::from_filename(env_file).ok();
+    // use the accounts from the configuration file if there are any, otherwise use
+    // the single account described by the environment
+    let accounts = if configured > 0 {
+        let mut accounts = config
+            .accounts
+            .value
+            .iter()
+            .map(|a| Account::from_config(a, &defaults))
+            .collect::<Result<Vec<Account>, ReddSaverError>>()?;
+        if let Some(password) = password {
+            accounts[0].password = Some(password);
+        }
+        accounts
+    } else {
+        vec![Account::from_env(defaults, password)?]
+    };
 
-    // initialize logger for the app and set logging level to info if no environment variable present
-    let env = Env::default().filter("RS_LOG").default_filter_or("info");
-    env_logger::Builder::from_env(env).init();
+    if let Some(login_matches) = matches.subcommand_matches("login") {
+        let account = match login_matches.value_of("account") {
+            Some(name) => accounts
+                .iter()
+                .find(|a| a.name == name)
+                .ok_or_else(|| ReddSaverError::AccountNotFound(String::from(name)))?,
+            None => accounts
+                .first()
+                .ok_or_else(|| ReddSaverError::AccountNotFound(String::from("<ANY>")))?,
+        };
 
-    let client_id = env::var("CLIENT_ID")?;
-    let client_secret = env::var("CLIENT_SECRET")?;
-    let username = env::var("USERNAME")?;
-    let password = env::var("PASSWORD")?;
-    let user_agent = get_user_agent_string(None, None);
+        let http = Http::new(&config.http_settings())?;
+        return login(&http, account, redirect_uri).await;
+    }
 
-    if !check_path_present(&data_directory) {
-        return Err(DataDirNotFound);
+    for account in &accounts {
+        if !check_path_present(&account.data_directory) {
+            return Err(DataDirNotFound);
+        }
     }
 
     // if the option is show-config, show the configuration and return immediately
     if matches.is_present("show_config") {
         info!("Current configuration:");
-        info!("ENVIRONMENT_FILE = {}", &env_file);
-        info!("DATA_DIRECTORY = {}", &data_directory);
-        info!("CLIENT_ID = {}", &client_id);
-        info!("CLIENT_SECRET = {}", mask_sensitive(&client_secret));
-        info!("USERNAME = {}", &username);
-        info!("PASSWORD = {}", mask_sensitive(&password));
-        info!("USER_AGENT = {}", &user_agent);
-        info!("SUBREDDITS = {}", print_subreddits(&subreddits));
-        info!("UNSAVE = {}", unsave);
+        info!(
+            "CONFIG_FILE = {}",
+            config.config_file.as_deref().unwrap_or("<NONE>")
+        );
+        info!(
+            "PROFILE = {}",
+            config.profile.as_deref().unwrap_or("<NONE>")
+        );
+        info!("ENVIRONMENT_FILE = {}", print_setting(&config.env_file));
+        info!("DATA_DIRECTORY = {}", print_setting(&config.data_directory));
+        info!("DRY_RUN = {}", print_setting(&config.dry_run));
+        info!("HUMAN_READABLE = {}", print_setting(&config.human_readable));
+        info!(
+            "SUBREDDITS = {} (from {})",
+            print_subreddits(&config.subreddits.value),
+            config.subreddits.layer
+        );
+        info!("UNSAVE = {}", print_setting(&config.unsave));
+        info!("CONCURRENT = {}", print_setting(&config.concurrent));
+        info!("REDIRECT_URI = {}", print_setting(&config.redirect_uri));
+        info!(
+            "USER_AGENT = {} (from {})",
+            config
+                .user_agent
+                .value
+                .as_deref()
+                .unwrap_or("<PER ACCOUNT>"),
+            config.user_agent.layer
+        );
+        info!(
+            "CONNECT_TIMEOUT = {}",
+            print_setting(&config.connect_timeout)
+        );
+        info!("READ_TIMEOUT = {}", print_setting(&config.read_timeout));
+        info!(
+            "PROXY = {} (from {})",
+            config.proxy.value.as_deref().unwrap_or("<NONE>"),
+            config.proxy.layer
+        );
+        info!(
+            "CA_CERTS = {} (from {})",
+            if config.ca_certs.value.is_empty() {
+                String::from("<NONE>")
+            } else {
+                config.ca_certs.value.join(",")
+            },
+            config.ca_certs.layer
+        );
+        info!(
+            "POOL_MAX_IDLE_PER_HOST = {}",
+            print_setting(&config.pool_max_idle_per_host)
+        );
+        info!("MAX_DOWNLOADS = {}", print_setting(&config.max_downloads));
+        info!("MAX_PER_HOST = {}", print_setting(&config.max_per_host));
+        info!("RETRY_ATTEMPTS = {}", print_setting(&config.retry_attempts));
+        info!("RETRY_DELAY = {}", print_setting(&config.retry_delay));
+        info!("VIDEO_QUALITY = {}", print_setting(&config.video_quality));
+        info!("MUXER = {}", print_setting(&config.muxer));
+        info!(
+            "HOST_LIMITS = {} (from {})",
+            print_host_limits(&config.host_limits.value),
+            config.host_limits.layer
+        );
+        info!(
+            "RECORD = {} (from {})",
+            config.record.value.as_deref().unwrap_or("<NONE>"),
+            config.record.layer
+        );
+        info!(
+            "REPLAY = {} (from {})",
+            config.replay.value.as_deref().unwrap_or("<NONE>"),
+            config.replay.layer
+        );
+        info!("REDDIT_URL = {}", print_setting(&config.reddit_url));
+        info!("OAUTH_URL = {}", print_setting(&config.oauth_url));
+        info!(
+            "REDDIT_MEDIA_URL = {}",
+            print_setting(&config.reddit_media_url)
+        );
+        info!("GFYCAT_API_URL = {}", print_setting(&config.gfycat_api_url));
+        info!(
+            "REDGIFS_API_URL = {}",
+            print_setting(&config.redgifs_api_url)
+        );
+        info!(
+            "GIPHY_MEDIA_URL = {}",
+            print_setting(&config.giphy_media_url)
+        );
+        info!(
+            "ACCOUNTS = {} (from {})",
+            accounts.len(),
+            config.accounts.layer
+        );
+        for account in &accounts {
+            let refresh_token = credentials::load_refresh_token(&account.username)?;
+
+            info!("[{}]", account.name);
+            info!("DATA_DIRECTORY = {}", &account.data_directory);
+            info!("CLIENT_ID = {}", &account.client_id);
+            info!(
+                "CLIENT_SECRET = {} (from {})",
+                mask_sensitive(&account.client_secret.value),
+                account.client_secret.source
+            );
+            info!("USERNAME = {}", &account.username);
+            info!("USER_AGENT = {}", &account.user_agent);
+            info!("PASSWORD = {}", print_secret(&account.password));
+            info!("TOTP_SECRET = {}", print_secret(&account.totp_secret));
+            info!(
+                "REFRESH_TOKEN = {}",
+                mask_sensitive(refresh_token.as_deref().unwrap_or_default())
+            );
+            info!("SUBREDDITS = {}", print_subreddits(&account.subreddits));
+            info!("UNSAVE = {}", account.unsave);
+        }
 
         return Ok(());
     }
 
+    // only make another attempt at what failed before, instead of going through all saved posts
+    let retry = match matches.subcommand_matches("retry") {
+        Some(m) => Some(match m.value_of("max_attempts") {
+            Some(v) => v.parse::<u32>().map_err(|_| {
+                ReddSaverError::InvalidSetting(String::from("--max-attempts"), String::from(v))
+            })?,
+            None => DEFAULT_MAX_ATTEMPTS,
+        }),
+        None => None,
+    };
+
+    // all accounts share one client, so connections to the same hosts are reused
+    let http = Http::new(&config.http_settings())?;
+    let limits = config.download_limits();
+    let video = config.video_settings();
+    let run = |account| {
+        process_account(
+            &http,
+            &limits,
+            account,
+            should_download,
+            use_human_readable,
+            video,
+            retry,
+        )
+    };
+    let results: Vec<Result<Summary, ReddSaverError>> = if config.concurrent.value {
+        join_all(accounts.iter().map(run)).await
+    } else {
+        let mut results = Vec::new();
+        for account in &accounts {
+            results.push(run(account).await);
+        }
+        results
+    };
+
+    let mut combined = Summary::default();
+    let mut failed = 0;
+    for (account, result) in accounts.iter().zip(results) {
+        match result {
+            Ok(summary) => {
+                if accounts.len() > 1 {
+                    summary.log(&format!("Download Summary for {}", account.name));
+                }
+                combined = combined + summary;
+            }
+            Err(e) => {
+                error!("Could not process account {}: {}", account.name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    combined.log("Download Summary");
+    info!("FIN.");
+
+    if failed > 0 {
+        return Err(ReddSaverError::AccountsFailed(failed));
+    }
+
+    Ok(())
+}
+
+/// Authorize reddsaver in the browser and store the refresh token for the account
+async fn login(http: &Http, account: &Account, redirect_uri: &str) -> Result<(), ReddSaverError> {
+    let client = Client::new(
+        http,
+        &account.client_id,
+        &account.client_secret.value,
+        &account.username,
+        None,
+        &account.user_agent,
+    );
+    let state = random_state();
+    let authorize_url = client.authorize_url(redirect_uri, &state)?;
+    info!("Open the following URL in your browser to authorize reddsaver:");
+    info!("{}", authorize_url);
+
+    let code = redirect::wait_for_code(redirect_uri, &state).await?;
+    let auth = client.exchange_code(&code, redirect_uri).await?;
+    match auth.refresh_token {
+        Some(token) => {
+            let path = credentials::save_refresh_token(&account.username, &token)?;
+            info!(
+                "Stored refresh token for {} in {}",
+                account.username,
+                path.display()
+            );
+            Ok(())
+        }
+        None => Err(ReddSaverError::LoginFailed(String::from(
+            "no refresh token was returned",
+        ))),
+    }
+}
+
+/// Log in to a single account and download its saved media, or only retry the ones that
+/// failed before, giving up on them after the given number of attempts
+async fn process_account(
+    http: &Http,
+    limits: &DownloadLimits,
+    account: &Account,
+    should_download: bool,
+    use_human_readable: bool,
+    video: VideoSettings,
+    retry: Option<u32>,
+) -> Result<Summary, ReddSaverError> {
+    let refresh_token = credentials::load_refresh_token(&account.username)?;
+    let totp = match &account.totp_secret {
+        Some(seed) => Some(Totp::from_base32(&seed.value)?),
+        None => None,
+    };
+
     // login to reddit using the credentials provided and get API bearer token
-    let auth = Client::new(
-        &client_id,
-        &client_secret,
-        &username,
-        &password,
-        &user_agent,
+    // the session renews the access token on its own during long runs
+    let client = Client::new(
+        http,
+        &account.client_id,
+        &account.client_secret.value,
+        &account.username,
+        account.password.as_ref().map(|p| p.value.as_str()),
+        &account.user_agent,
     )
-    .login()
-    .await?;
-    info!("Successfully logged in to Reddit as {}", username);
-    debug!("Authentication details: {:#?}", auth);
+    .refresh_token(refresh_token.as_deref())
+    .totp(totp.as_ref());
+    // reuse the access token from a previous run if it is still valid, unless recording
+    // or replaying, where every run has to log in the same way
+    let cache = if http.uses_fixtures() {
+        None
+    } else {
+        TokenCache::new(&account.username).ok()
+    };
+    let session = Session::new(client, cache).await?;
+    info!("Successfully logged in to Reddit as {}", account.username);
+    debug!("Authentication details: {:#?}", session);
 
     // get information about the user to display
-    let user = User::new(&auth, &username);
+    let user = User::new(&session, &account.username);
 
     let user_info = user.about().await?;
     info!("The user details are: ");
@@ -154,21 +585,20 @@ async fn main() -> Result<(), ReddSaverError> {
     info!("Link Karma: {:#?}", user_info.data.link_karma);
 
     info!("Starting data gathering from Reddit. This might take some time. Hold on....");
-    // get the saved posts for this particular user
-    let saved = user.saved().await?;
-    debug!("Saved Posts: {:#?}", saved);
-
+    // the saved posts for this particular user are fetched while their media are downloaded
     let downloader = Downloader::new(
         &user,
-        &saved,
-        &data_directory,
-        &subreddits,
+        limits,
+        &account.data_directory,
+        &account.subreddits,
         should_download,
         use_human_readable,
-        unsave,
-    );
-
-    downloader.run().await?;
+        account.unsave,
+    )
+    .video(video);
 
-    Ok(())
+    match retry {
+        Some(max_attempts) => downloader.retry(max_attempts).await,
+        None => downloader.run().await,
+    }
 }
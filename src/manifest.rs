@@ -0,0 +1,487 @@
+use crate::errors::ReddSaverError;
+
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use url::Url;
+
+/// Single file Reddit serves the audio of a video with an HLS playlist as, next to the playlist
+static HLS_AUDIO_FILE: &str = "DASH_AUDIO_128.mp4";
+/// Bits per second of `HLS_AUDIO_FILE`
+static HLS_AUDIO_BANDWIDTH: u64 = 128_000;
+
+/// Highest video quality to download, set with `--video-quality`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum VideoQuality {
+    Best,
+    /// Highest height in pixels, e.g. 720 for 720p
+    Max(u32),
+}
+
+impl FromStr for VideoQuality {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        if s == "best" {
+            return Ok(VideoQuality::Best);
+        }
+        match s.trim_end_matches('p').parse() {
+            Ok(height) if height > 0 => Ok(VideoQuality::Max(height)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TryFrom<String> for VideoQuality {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .parse()
+            .map_err(|_| format!("invalid video quality `{}`", value))
+    }
+}
+
+impl fmt::Display for VideoQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VideoQuality::Best => write!(f, "best"),
+            VideoQuality::Max(height) => write!(f, "{}p", height),
+        }
+    }
+}
+
+/// Kind of stream a representation in a manifest holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamKind {
+    Video,
+    Audio,
+}
+
+/// One of the versions of a stream listed in a manifest, e.g. the 720p video
+#[derive(Debug, Clone, PartialEq)]
+pub struct Representation {
+    pub kind: StreamKind,
+    /// Where the representation is downloaded from
+    pub url: String,
+    /// Bits per second
+    pub bandwidth: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// Representations listed in a DASH manifest, with their URLs resolved against `manifest_url`.
+/// Reddit serves every representation as a single file, so only their base URLs are read
+pub fn parse_dash(
+    manifest: &str,
+    manifest_url: &str,
+) -> Result<Vec<Representation>, ReddSaverError> {
+    let invalid = |reason: &str| {
+        ReddSaverError::InvalidManifest(String::from(manifest_url), String::from(reason))
+    };
+    let mut base = Url::parse(manifest_url)?;
+
+    let mut representations = Vec::new();
+    // attributes of the adaptation set and representation the parser is in
+    let mut adaptation_set: Vec<(String, String)> = Vec::new();
+    let mut representation: Option<Vec<(String, String)>> = None;
+    let mut rest = manifest;
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        rest = &rest[start..];
+        if rest.starts_with("<!--") {
+            let end = rest
+                .find("-->")
+                .ok_or_else(|| invalid("unterminated comment"))?;
+            rest = &rest[end + 3..];
+            continue;
+        }
+        let end = rest.find('>').ok_or_else(|| invalid("unterminated tag"))?;
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        if let Some(name) = tag.strip_prefix('/') {
+            match name.trim() {
+                "AdaptationSet" => adaptation_set.clear(),
+                "Representation" => representation = None,
+                "BaseURL" => {
+                    let url = base.join(unescape(text.trim()).as_str())?;
+                    match &representation {
+                        Some(attributes) => {
+                            // representations inherit the attributes of their adaptation set
+                            let value = |name: &str| {
+                                attribute(attributes, name)
+                                    .or_else(|| attribute(&adaptation_set, name))
+                            };
+                            let kind = match stream_kind(value("contentType"), value("mimeType")) {
+                                Some(kind) => kind,
+                                None => continue,
+                            };
+                            let size = |name: &str| value(name).and_then(|v| v.parse().ok());
+                            representations.push(Representation {
+                                kind,
+                                url: url.to_string(),
+                                bandwidth: value("bandwidth")
+                                    .and_then(|v| v.parse().ok())
+                                    .unwrap_or_default(),
+                                width: size("width"),
+                                height: size("height"),
+                            });
+                        }
+                        // base URL of the whole manifest, which the others are relative to
+                        None => base = url,
+                    }
+                }
+                _ => (),
+            }
+            continue;
+        }
+
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let name = tag.split_whitespace().next().unwrap_or_default();
+        match name {
+            "AdaptationSet" if !self_closing => adaptation_set = attributes(tag),
+            "Representation" if !self_closing => representation = Some(attributes(tag)),
+            _ => (),
+        }
+    }
+
+    Ok(representations)
+}
+
+fn stream_kind(content_type: Option<&str>, mime_type: Option<&str>) -> Option<StreamKind> {
+    let kind = content_type.or_else(|| mime_type.and_then(|m| m.split('/').next()))?;
+    match kind {
+        "video" => Some(StreamKind::Video),
+        "audio" => Some(StreamKind::Audio),
+        _ => None,
+    }
+}
+
+fn attribute<'a>(attributes: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Attributes of a start tag, e.g. `Representation id="1" bandwidth="800000"`
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    // skip the name of the tag
+    let mut rest = tag.trim_start();
+    rest = &rest[rest.find(char::is_whitespace).unwrap_or(rest.len())..];
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals].trim();
+        let value = rest[equals + 1..].trim_start();
+        let quote = match value.chars().next() {
+            Some(q) if q == '"' || q == '\'' => q,
+            _ => break,
+        };
+        let end = match value[1..].find(quote) {
+            Some(end) => end + 1,
+            None => break,
+        };
+        attributes.push((String::from(name), unescape(&value[1..end])));
+        rest = &value[end + 1..];
+    }
+
+    attributes
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The audio representation with the highest bandwidth, if there is any audio at all
+pub fn best_audio(representations: &[Representation]) -> Option<&Representation> {
+    representations
+        .iter()
+        .filter(|r| r.kind == StreamKind::Audio)
+        .max_by_key(|r| r.bandwidth)
+}
+
+/// Representations listed in an HLS master playlist, with their URIs resolved against
+/// `playlist_url`. Variants are read as video and audio renditions as audio. Reddit serves
+/// every variant as a single file as well, named after its height like in DASH manifests,
+/// so variants with a resolution point to that file instead of their playlist of segments.
+/// Audio renditions point to the single file of the audio in the same way
+pub fn parse_hls(
+    playlist: &str,
+    playlist_url: &str,
+) -> Result<Vec<Representation>, ReddSaverError> {
+    let base = Url::parse(playlist_url)?;
+    let mut lines = playlist.lines().map(str::trim);
+    if lines.next() != Some("#EXTM3U") {
+        return Err(ReddSaverError::InvalidManifest(
+            String::from(playlist_url),
+            String::from("missing #EXTM3U header"),
+        ));
+    }
+
+    let mut representations = Vec::new();
+    // attributes of the last variant, whose URI is on the next line
+    let mut variant: Option<Vec<(String, String)>> = None;
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(list) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            variant = Some(attribute_list(list));
+        } else if let Some(list) = line.strip_prefix("#EXT-X-MEDIA:") {
+            let attributes = attribute_list(list);
+            if let (Some("AUDIO"), Some(uri)) = (
+                attribute(&attributes, "TYPE"),
+                attribute(&attributes, "URI"),
+            ) {
+                let mut url = base.join(uri)?;
+                let mut bandwidth = 0;
+                if is_playlist(url.as_str()) {
+                    url = base.join(HLS_AUDIO_FILE)?;
+                    bandwidth = HLS_AUDIO_BANDWIDTH;
+                }
+                representations.push(Representation {
+                    kind: StreamKind::Audio,
+                    url: url.to_string(),
+                    bandwidth,
+                    width: None,
+                    height: None,
+                });
+            }
+        } else if !line.starts_with('#') {
+            if let Some(attributes) = variant.take() {
+                let mut resolution = attribute(&attributes, "RESOLUTION")
+                    .unwrap_or_default()
+                    .splitn(2, 'x')
+                    .map(|v| v.parse().ok());
+                let width = resolution.next().flatten();
+                let height = resolution.next().flatten();
+                let mut url = base.join(line)?;
+                match height {
+                    Some(height) if is_playlist(url.as_str()) => {
+                        url = base.join(&format!("DASH_{}.mp4", height))?
+                    }
+                    _ => (),
+                }
+                representations.push(Representation {
+                    kind: StreamKind::Video,
+                    url: url.to_string(),
+                    bandwidth: attribute(&attributes, "BANDWIDTH")
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or_default(),
+                    width,
+                    height,
+                });
+            }
+        }
+    }
+
+    Ok(representations)
+}
+
+/// Attributes of an HLS tag, e.g. `BANDWIDTH=800000,CODECS="avc1,mp4a"`
+fn attribute_list(list: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = list;
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals].trim();
+        let value = &rest[equals + 1..];
+        let (value, next) = if let Some(quoted) = value.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match value.find(',') {
+                Some(end) => (&value[..end], &value[end..]),
+                None => (value, ""),
+            }
+        };
+        attributes.push((String::from(name), String::from(value)));
+        rest = next.trim_start_matches(',');
+    }
+
+    attributes
+}
+
+/// Whether a representation is served as a playlist of segments rather than a single file
+pub fn is_playlist(url: &str) -> bool {
+    Url::parse(url)
+        .map(|u| u.path().to_lowercase().ends_with(".m3u8"))
+        .unwrap_or(false)
+}
+
+/// The video representation with the highest resolution and bandwidth allowed by `quality`.
+/// When every representation is above it, the lowest one is picked
+pub fn best_video(
+    representations: &[Representation],
+    quality: VideoQuality,
+) -> Option<&Representation> {
+    let videos = representations
+        .iter()
+        .filter(|r| r.kind == StreamKind::Video);
+    let allowed = |r: &&Representation| match quality {
+        VideoQuality::Best => true,
+        VideoQuality::Max(max) => match r.height {
+            Some(height) => height <= max,
+            None => true,
+        },
+    };
+
+    videos
+        .clone()
+        .filter(allowed)
+        .max_by_key(|r| (r.height, r.bandwidth))
+        .or_else(|| videos.min_by_key(|r| (r.height, r.bandwidth)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DASH: &str = include_str!("../tests/fixtures/manifests/DASHPlaylist.mpd");
+    static HLS: &str = include_str!("../tests/fixtures/manifests/HLSPlaylist.m3u8");
+
+    fn summary(representations: &[Representation]) -> Vec<(StreamKind, &str, u64, Option<u32>)> {
+        representations
+            .iter()
+            .map(|r| (r.kind, r.url.as_str(), r.bandwidth, r.height))
+            .collect()
+    }
+
+    #[test]
+    fn representations_are_read_from_dash_manifests() {
+        let representations =
+            parse_dash(DASH, "https://v.redd.it/abc/DASHPlaylist.mpd?x=1").unwrap();
+
+        assert_eq!(
+            summary(&representations),
+            vec![
+                (
+                    StreamKind::Video,
+                    "https://v.redd.it/abc/DASH_270.mp4",
+                    330_138,
+                    Some(270)
+                ),
+                (
+                    StreamKind::Video,
+                    "https://v.redd.it/abc/DASH_360.mp4",
+                    611_418,
+                    Some(360)
+                ),
+                (
+                    StreamKind::Video,
+                    "https://v.redd.it/abc/DASH_720.mp4?a=1&b=2",
+                    2_437_491,
+                    Some(720)
+                ),
+                (
+                    StreamKind::Video,
+                    "https://v.redd.it/abc/DASH_1080.mp4",
+                    4_755_622,
+                    Some(1080)
+                ),
+                (
+                    StreamKind::Audio,
+                    "https://v.redd.it/abc/DASH_AUDIO_64.mp4",
+                    67_982,
+                    None
+                ),
+                (
+                    StreamKind::Audio,
+                    "https://v.redd.it/abc/DASH_AUDIO_128.mp4",
+                    132_258,
+                    None
+                ),
+            ]
+        );
+        assert_eq!(
+            best_audio(&representations).unwrap().url,
+            "https://v.redd.it/abc/DASH_AUDIO_128.mp4"
+        );
+    }
+
+    #[test]
+    fn variants_are_read_from_hls_playlists() {
+        let representations = parse_hls(HLS, "https://v.redd.it/abc/HLSPlaylist.m3u8?a=1").unwrap();
+
+        assert_eq!(
+            summary(&representations),
+            vec![
+                (
+                    StreamKind::Audio,
+                    "https://v.redd.it/abc/DASH_AUDIO_128.mp4",
+                    128_000,
+                    None
+                ),
+                (
+                    StreamKind::Video,
+                    "https://v.redd.it/abc/DASH_270.mp4",
+                    541_958,
+                    Some(270)
+                ),
+                (
+                    StreamKind::Video,
+                    "https://v.redd.it/abc/DASH_360.mp4",
+                    825_108,
+                    Some(360)
+                ),
+                (
+                    StreamKind::Video,
+                    "https://v.redd.it/abc/DASH_720.mp4",
+                    2_611_944,
+                    Some(720)
+                ),
+                (
+                    StreamKind::Video,
+                    "https://v.redd.it/abc/DASH_1080.mp4",
+                    5_185_396,
+                    Some(1080)
+                ),
+            ]
+        );
+        assert_eq!(representations[1].width, Some(480));
+        assert!(!is_playlist(&representations[1].url));
+        // the audio is found from the playlist alone
+        assert_eq!(
+            best_audio(&representations).unwrap().url,
+            "https://v.redd.it/abc/DASH_AUDIO_128.mp4"
+        );
+
+        // without a resolution, the file of a variant cannot be found
+        let unknown = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1000\nHLS_720.m3u8\n";
+        let representations = parse_hls(unknown, "https://v.redd.it/abc/HLSPlaylist.m3u8").unwrap();
+        assert!(is_playlist(&representations[0].url));
+        assert!(parse_hls("<MPD>", "https://v.redd.it/abc/HLSPlaylist.m3u8").is_err());
+    }
+
+    #[test]
+    fn the_best_video_within_the_quality_is_picked() {
+        let representations = parse_dash(DASH, "https://v.redd.it/abc/DASHPlaylist.mpd").unwrap();
+        let height = |quality| best_video(&representations, quality).unwrap().height;
+
+        assert_eq!(height(VideoQuality::Best), Some(1080));
+        assert_eq!(height(VideoQuality::Max(720)), Some(720));
+        assert_eq!(height(VideoQuality::Max(480)), Some(360));
+        assert_eq!(height(VideoQuality::Max(144)), Some(270));
+        assert_eq!(best_video(&representations[4..], VideoQuality::Best), None);
+
+        assert_eq!("720p".parse(), Ok(VideoQuality::Max(720)));
+        assert_eq!("1080".parse(), Ok(VideoQuality::Max(1080)));
+        assert_eq!("Best".parse(), Ok(VideoQuality::Best));
+        assert!("0p".parse::<VideoQuality>().is_err());
+        assert!("high".parse::<VideoQuality>().is_err());
+        assert_eq!(VideoQuality::Max(480).to_string(), "480p");
+    }
+}
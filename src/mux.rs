@@ -0,0 +1,888 @@
+use crate::errors::ReddSaverError;
+
+use serde::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::process::Command;
+
+/// Boxes that hold other boxes, and are descended into when tracks are renumbered or moved
+static CONTAINERS: [&[u8; 4]; 9] = [
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"mvex", b"moof", b"traf", b"edts",
+];
+/// Top level boxes that describe the layout of the original file, and would be wrong after
+/// the merge. Players do not need them to play the merged file
+static DROPPED: [&[u8; 4]; 7] = [
+    b"ftyp", b"moov", b"sidx", b"styp", b"mfra", b"free", b"skip",
+];
+/// Top level boxes that are read into memory, as the merge changes them. Every other box is
+/// copied from its file
+static LOADED: [&[u8; 4]; 3] = [b"ftyp", b"moov", b"moof"];
+/// The `tfhd` flag telling that the fragment gives the absolute position of its data
+const BASE_DATA_OFFSET_PRESENT: u32 = 0x01;
+
+/// Change to a box, returning the payload to replace it with if it needs to be changed
+type Edit<'e> = dyn FnMut(&Mp4Box) -> Result<Option<Vec<u8>>, ReddSaverError> + 'e;
+
+/// How the separate video and audio tracks of a video are merged into one file
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Muxer {
+    /// The MP4 writer built into reddsaver, which needs nothing else to be installed
+    Builtin,
+    /// `ffmpeg`, which has to be installed and on the `PATH`
+    Ffmpeg,
+}
+
+impl FromStr for Muxer {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "builtin" => Ok(Muxer::Builtin),
+            "ffmpeg" => Ok(Muxer::Ffmpeg),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Muxer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Muxer::Builtin => write!(f, "builtin"),
+            Muxer::Ffmpeg => write!(f, "ffmpeg"),
+        }
+    }
+}
+
+impl Muxer {
+    /// Merge the MP4 files holding the video and the audio track of a video into `output`
+    pub async fn merge(
+        self,
+        video: &Path,
+        audio: &Path,
+        output: &Path,
+    ) -> Result<(), ReddSaverError> {
+        match self {
+            Muxer::Builtin => {
+                let (video, audio, output) = (
+                    PathBuf::from(video),
+                    PathBuf::from(audio),
+                    PathBuf::from(output),
+                );
+                tokio::task::spawn_blocking(move || -> Result<(), ReddSaverError> {
+                    let mut merged = BufWriter::new(File::create(output)?);
+                    merge_mp4(
+                        &mut File::open(video)?,
+                        &mut File::open(audio)?,
+                        &mut merged,
+                    )?;
+                    merged.flush()?;
+                    Ok(())
+                })
+                .await?
+            }
+            Muxer::Ffmpeg => {
+                let status = Command::new("ffmpeg")
+                    .arg("-y")
+                    .arg("-loglevel")
+                    .arg("error")
+                    .arg("-i")
+                    .arg(video)
+                    .arg("-i")
+                    .arg(audio)
+                    .arg("-map")
+                    .arg("0:v")
+                    .arg("-map")
+                    .arg("1:a")
+                    .arg("-c")
+                    .arg("copy")
+                    // the output is a partial file, so its extension does not tell the format
+                    .arg("-f")
+                    .arg("mp4")
+                    .arg(output)
+                    .status()
+                    .await
+                    .map_err(|e| {
+                        ReddSaverError::MuxFailed(format!("could not run ffmpeg: {}", e))
+                    })?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(ReddSaverError::MuxFailed(format!(
+                        "ffmpeg exited with {}",
+                        status
+                    )))
+                }
+            }
+        }
+    }
+}
+
+/// A box of an MP4 file, the unit everything in the file is stored in
+#[derive(Debug)]
+struct Mp4Box<'a> {
+    kind: [u8; 4],
+    /// Length of the header, which is longer for boxes with a 64 bit size
+    header: usize,
+    /// The whole box, including its header
+    bytes: &'a [u8],
+    /// Position of the box in its file
+    offset: u64,
+}
+
+impl<'a> Mp4Box<'a> {
+    fn payload(&self) -> &'a [u8] {
+        &self.bytes[self.header..]
+    }
+
+    fn children(&self) -> Result<Vec<Mp4Box<'a>>, ReddSaverError> {
+        parse_boxes(self.payload(), self.offset + self.header as u64)
+    }
+
+    /// First box at the given path below this one
+    fn find(&self, path: &[&[u8; 4]]) -> Result<Option<Mp4Box<'a>>, ReddSaverError> {
+        let (first, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        for child in self.children()? {
+            if &child.kind == *first {
+                return if rest.is_empty() {
+                    Ok(Some(child))
+                } else {
+                    child.find(rest)
+                };
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn is(&self, kind: &[u8; 4]) -> bool {
+        &self.kind == kind
+    }
+}
+
+/// Split `data`, found at `offset` in its file, into the boxes it consists of
+fn parse_boxes(data: &[u8], offset: u64) -> Result<Vec<Mp4Box<'_>>, ReddSaverError> {
+    let mut boxes = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let rest = &data[position..];
+        if rest.len() < 8 {
+            return Err(invalid("truncated box header"));
+        }
+        let (size, header) = match read_u32(rest, 0)? {
+            // the box extends to the end of the file
+            0 => (rest.len(), 8),
+            1 => (read_u64(rest, 8)? as usize, 16),
+            size => (size as usize, 8),
+        };
+        if size < header || size > rest.len() {
+            return Err(invalid("box size out of bounds"));
+        }
+        let mut kind = [0; 4];
+        kind.copy_from_slice(&rest[4..8]);
+        boxes.push(Mp4Box {
+            kind,
+            header,
+            bytes: &rest[..size],
+            offset: offset + position as u64,
+        });
+        position += size;
+    }
+
+    Ok(boxes)
+}
+
+/// Top level box of a file being merged, whose contents are only read for the boxes in
+/// `LOADED`
+struct TopBox {
+    kind: [u8; 4],
+    /// Position of the box in its file
+    offset: u64,
+    /// Length of the whole box, including its header
+    length: u64,
+    /// Whether the header gives no size, as the box extends to the end of the file
+    to_end: bool,
+    bytes: Option<Vec<u8>>,
+}
+
+impl TopBox {
+    /// The box parsed from its contents, which have to have been read
+    fn contents(&self) -> Result<Mp4Box<'_>, ReddSaverError> {
+        let bytes = self.bytes.as_ref().ok_or_else(|| invalid("box not read"))?;
+        parse_boxes(bytes, self.offset)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| invalid("empty box"))
+    }
+
+    fn is(&self, kind: &[u8; 4]) -> bool {
+        &self.kind == kind
+    }
+}
+
+/// Top level boxes of a file, reading the contents of those in `LOADED` and skipping over the
+/// others
+fn read_top_boxes<R: Read + Seek>(file: &mut R) -> Result<Vec<TopBox>, ReddSaverError> {
+    let end = file.seek(SeekFrom::End(0))?;
+    let mut boxes = Vec::new();
+    let mut position = 0;
+    while position < end {
+        let available = (end - position).min(16) as usize;
+        if available < 8 {
+            return Err(invalid("truncated box header"));
+        }
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut header[..available])?;
+        let header = &header[..available];
+        let (length, header_length) = match read_u32(header, 0)? {
+            // the box extends to the end of the file
+            0 => (end - position, 8),
+            1 => (read_u64(header, 8)?, 16),
+            size => (size as u64, 8),
+        };
+        if length < header_length || length > end - position {
+            return Err(invalid("box size out of bounds"));
+        }
+        let mut kind = [0; 4];
+        kind.copy_from_slice(&header[4..8]);
+        let bytes = if LOADED.contains(&&kind) {
+            let mut bytes = vec![0; length as usize];
+            file.seek(SeekFrom::Start(position))?;
+            file.read_exact(&mut bytes)?;
+            Some(bytes)
+        } else {
+            None
+        };
+        boxes.push(TopBox {
+            kind,
+            offset: position,
+            length,
+            to_end: read_u32(header, 0)? == 0,
+            bytes,
+        });
+        position += length;
+    }
+
+    Ok(boxes)
+}
+
+/// Box with the given payload, keeping the 64 bit size of the original box if it had one so
+/// that the length of the box does not change
+fn write_box(kind: &[u8; 4], payload: &[u8], large: bool) -> Vec<u8> {
+    let large = large || payload.len() + 8 > u32::MAX as usize;
+    let mut bytes = Vec::with_capacity(payload.len() + 16);
+    if large {
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        bytes.extend_from_slice(kind);
+        bytes.extend_from_slice(&(payload.len() as u64 + 16).to_be_bytes());
+    } else {
+        bytes.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+        bytes.extend_from_slice(kind);
+    }
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Copy of the box in which `edit` may replace the payload of any box inside, descending into
+/// container boxes it leaves alone
+fn rebuild(b: &Mp4Box, edit: &mut Edit) -> Result<Vec<u8>, ReddSaverError> {
+    if let Some(payload) = edit(b)? {
+        return Ok(write_box(&b.kind, &payload, b.header == 16));
+    }
+    if !CONTAINERS.contains(&&b.kind) {
+        return Ok(b.bytes.to_vec());
+    }
+
+    let mut payload = Vec::with_capacity(b.payload().len());
+    for child in b.children()? {
+        payload.extend(rebuild(&child, edit)?);
+    }
+    Ok(write_box(&b.kind, &payload, b.header == 16))
+}
+
+/// One of the two files being merged
+struct Input<'a> {
+    moov: Mp4Box<'a>,
+    /// Top level boxes copied into the merged file, e.g. `mdat` and `moof`
+    media: Vec<&'a TopBox>,
+    /// Units per second of the durations of the movie
+    timescale: u32,
+    duration: u64,
+}
+
+impl<'a> Input<'a> {
+    fn parse(boxes: &'a [TopBox]) -> Result<Self, ReddSaverError> {
+        let mut moov = None;
+        let mut media = Vec::new();
+        for b in boxes {
+            if b.is(b"moov") {
+                moov = Some(b.contents()?);
+            } else if !DROPPED.contains(&&b.kind) {
+                media.push(b);
+            }
+        }
+        let moov = moov.ok_or_else(|| invalid("no movie header"))?;
+        let mvhd = moov
+            .find(&[b"mvhd"])?
+            .ok_or_else(|| invalid("no movie header"))?;
+        let (timescale, duration) = movie_times(mvhd.payload())?;
+
+        Ok(Self {
+            moov,
+            media,
+            timescale,
+            duration,
+        })
+    }
+
+    fn tracks(&self) -> Result<Vec<Mp4Box<'a>>, ReddSaverError> {
+        Ok(self
+            .moov
+            .children()?
+            .into_iter()
+            .filter(|b| b.is(b"trak"))
+            .collect())
+    }
+
+    fn is_fragmented(&self) -> Result<bool, ReddSaverError> {
+        Ok(self.moov.find(&[b"mvex"])?.is_some())
+    }
+
+    /// Position in the merged file of what was at `offset` in this file, given where the
+    /// media boxes of this file start in the merged file
+    fn relocate(&self, offset: u64, start: u64) -> Result<u64, ReddSaverError> {
+        let mut position = start;
+        for b in &self.media {
+            if offset >= b.offset && offset < b.offset + b.length {
+                return Ok(position + offset - b.offset);
+            }
+            position += b.length;
+        }
+
+        Err(invalid("media data outside of the file"))
+    }
+
+    fn media_length(&self) -> u64 {
+        self.media.iter().map(|b| b.length).sum()
+    }
+}
+
+/// Merge an MP4 file holding the video track of a video with one holding its audio track.
+/// Both regular and fragmented MP4 files are supported, as long as both are of the same kind.
+/// Only the boxes describing the media are held in memory, the media data is copied over
+fn merge_mp4<R: Read + Seek, W: Write>(
+    video_file: &mut R,
+    audio_file: &mut R,
+    output: &mut W,
+) -> Result<(), ReddSaverError> {
+    let video_boxes = read_top_boxes(video_file)?;
+    let audio_boxes = read_top_boxes(audio_file)?;
+    let ftyp = video_boxes
+        .iter()
+        .find(|b| b.is(b"ftyp"))
+        .and_then(|b| b.bytes.clone())
+        .unwrap_or_default();
+    let video = Input::parse(&video_boxes)?;
+    let audio = Input::parse(&audio_boxes)?;
+    if video.is_fragmented()? != audio.is_fragmented()? {
+        return Err(invalid("cannot merge a fragmented file with a regular one"));
+    }
+    let audio_tracks = audio.tracks()?;
+    if audio_tracks.len() != 1 {
+        return Err(invalid("the audio file does not hold exactly one track"));
+    }
+
+    // the audio track is numbered after the video tracks
+    let mut audio_id = 1;
+    for trak in video.tracks()? {
+        let tkhd = trak
+            .find(&[b"tkhd"])?
+            .ok_or_else(|| invalid("no track header"))?;
+        audio_id = audio_id.max(track_id(tkhd.payload())? + 1);
+    }
+    let audio_duration = scale(audio.duration, audio.timescale, video.timescale);
+    let duration = video.duration.max(audio_duration);
+
+    // the size of the movie box does not depend on where the media end up, so it is built
+    // once to find out where the media start and then again with the right positions
+    let length = build_moov(&video, &audio, audio_id, duration, 0)?.len() as u64;
+    let start = (ftyp.len() as u64) + length;
+    let moov = build_moov(&video, &audio, audio_id, duration, start)?;
+    debug_assert_eq!(moov.len() as u64, length);
+
+    output.write_all(&ftyp)?;
+    output.write_all(&moov)?;
+    let mut sequence = 0;
+    write_media(&video, video_file, None, start, &mut sequence, output)?;
+    let audio_start = start + video.media_length();
+    write_media(
+        &audio,
+        audio_file,
+        Some(audio_id),
+        audio_start,
+        &mut sequence,
+        output,
+    )?;
+
+    Ok(())
+}
+
+/// Write the media boxes of an input, which start at `start` in the merged file. Fragments
+/// are numbered after `sequence` and moved to the track `id` when there is one
+fn write_media<R: Read + Seek, W: Write>(
+    input: &Input,
+    file: &mut R,
+    id: Option<u32>,
+    start: u64,
+    sequence: &mut u32,
+    output: &mut W,
+) -> Result<(), ReddSaverError> {
+    for b in &input.media {
+        if !b.is(b"moof") {
+            file.seek(SeekFrom::Start(b.offset))?;
+            let mut contents = file.by_ref().take(b.length);
+            let mut copied = 0;
+            // a box extending to the end of its file would take in what follows it in the
+            // merged file, so it is given its size
+            if b.to_end {
+                if b.length > u32::MAX as u64 {
+                    return Err(invalid("box extending to the end of the file too large"));
+                }
+                let mut header = [0; 8];
+                contents.read_exact(&mut header)?;
+                header[..4].copy_from_slice(&(b.length as u32).to_be_bytes());
+                output.write_all(&header)?;
+                copied = header.len() as u64;
+            }
+            if copied + io::copy(&mut contents, output)? != b.length {
+                return Err(invalid("media data cut short"));
+            }
+            continue;
+        }
+        // fragments are numbered in the order they appear in the merged file
+        *sequence += 1;
+        let sequence = *sequence;
+        let moof = rebuild(&b.contents()?, &mut |b| {
+            if b.is(b"mfhd") {
+                return Ok(Some(patch_u32(b.payload(), 4, sequence)?));
+            }
+            if b.is(b"tfhd") {
+                let mut payload = b.payload().to_vec();
+                if let Some(id) = id {
+                    payload = patch_u32(&payload, 4, id)?;
+                }
+                if read_u32(&payload, 0)? & BASE_DATA_OFFSET_PRESENT != 0 {
+                    let offset = input.relocate(read_u64(&payload, 8)?, start)?;
+                    payload = patch_u64(&payload, 8, offset)?;
+                }
+                return Ok(Some(payload));
+            }
+            Ok(None)
+        })?;
+        output.write_all(&moof)?;
+    }
+
+    Ok(())
+}
+
+/// Movie box of the merged file, with the audio track added to the video tracks and the
+/// positions of all media moved to where they are in the merged file, which starts at `start`
+fn build_moov(
+    video: &Input,
+    audio: &Input,
+    audio_id: u32,
+    duration: u64,
+    start: u64,
+) -> Result<Vec<u8>, ReddSaverError> {
+    let audio_start = start + video.media_length();
+    let audio_trak = rebuild(&audio.tracks()?[0], &mut |b| {
+        if b.is(b"tkhd") {
+            let payload = patch_u32(b.payload(), track_id_position(b.payload())?, audio_id)?;
+            let (position, duration) = track_duration(&payload)?;
+            let duration = scale(duration, audio.timescale, video.timescale);
+            return Ok(Some(patch_sized(&payload, position, duration)?));
+        }
+        if b.is(b"elst") {
+            return Ok(Some(scale_edits(
+                b.payload(),
+                audio.timescale,
+                video.timescale,
+            )?));
+        }
+        relocate_chunks(b, audio, audio_start)
+    })?;
+    let audio_trex = match audio.moov.find(&[b"mvex", b"trex"])? {
+        Some(trex) => Some(write_box(
+            b"trex",
+            &patch_u32(trex.payload(), 4, audio_id)?,
+            false,
+        )),
+        None => None,
+    };
+
+    let mut payload = Vec::new();
+    let children = video.moov.children()?;
+    let last_trak = children.iter().rposition(|b| b.is(b"trak"));
+    for (i, child) in children.iter().enumerate() {
+        if child.is(b"mvhd") {
+            let (position, _) = movie_duration(child.payload())?;
+            let mvhd = patch_sized(child.payload(), position, duration)?;
+            let mvhd = patch_u32(&mvhd, mvhd.len() - 4, audio_id + 1)?;
+            payload.extend(write_box(b"mvhd", &mvhd, child.header == 16));
+        } else if child.is(b"mvex") {
+            let mut mvex = Vec::new();
+            for b in child.children()? {
+                if b.is(b"mehd") {
+                    mvex.extend(write_box(b"mehd", &patch_mehd(&b, duration)?, false));
+                } else {
+                    mvex.extend_from_slice(b.bytes);
+                }
+            }
+            if let Some(trex) = &audio_trex {
+                mvex.extend_from_slice(trex);
+            }
+            payload.extend(write_box(b"mvex", &mvex, child.header == 16));
+        } else {
+            payload.extend(rebuild(child, &mut |b| relocate_chunks(b, video, start))?);
+        }
+        if Some(i) == last_trak {
+            payload.extend_from_slice(&audio_trak);
+        }
+    }
+
+    Ok(write_box(b"moov", &payload, video.moov.header == 16))
+}
+
+/// Chunk offset table with the offsets moved to where the media are in the merged file
+fn relocate_chunks(
+    b: &Mp4Box,
+    input: &Input,
+    start: u64,
+) -> Result<Option<Vec<u8>>, ReddSaverError> {
+    let wide = if b.is(b"stco") {
+        false
+    } else if b.is(b"co64") {
+        true
+    } else {
+        return Ok(None);
+    };
+
+    let mut payload = b.payload().to_vec();
+    let count = read_u32(&payload, 4)? as usize;
+    for i in 0..count {
+        if wide {
+            let position = 8 + i * 8;
+            let offset = input.relocate(read_u64(&payload, position)?, start)?;
+            payload = patch_u64(&payload, position, offset)?;
+        } else {
+            let position = 8 + i * 4;
+            let offset = input.relocate(read_u32(&payload, position)? as u64, start)?;
+            if offset > u32::MAX as u64 {
+                return Err(invalid("merged file too large for its chunk offsets"));
+            }
+            payload = patch_u32(&payload, position, offset as u32)?;
+        }
+    }
+
+    Ok(Some(payload))
+}
+
+/// Timescale and duration from the payload of an `mvhd` box
+fn movie_times(mvhd: &[u8]) -> Result<(u32, u64), ReddSaverError> {
+    let timescale_position = if mvhd.first() == Some(&1) { 20 } else { 12 };
+    let timescale = read_u32(mvhd, timescale_position)?;
+    if timescale == 0 {
+        return Err(invalid("movie without a timescale"));
+    }
+    let (_, duration) = movie_duration(mvhd)?;
+    Ok((timescale, duration))
+}
+
+/// Position and value of the duration in the payload of an `mvhd` box
+fn movie_duration(mvhd: &[u8]) -> Result<(usize, u64), ReddSaverError> {
+    if mvhd.first() == Some(&1) {
+        Ok((24, read_u64(mvhd, 24)?))
+    } else {
+        Ok((16, read_u32(mvhd, 16)? as u64))
+    }
+}
+
+fn track_id_position(tkhd: &[u8]) -> Result<usize, ReddSaverError> {
+    match tkhd.first() {
+        Some(1) => Ok(20),
+        Some(_) => Ok(12),
+        None => Err(invalid("empty track header")),
+    }
+}
+
+fn track_id(tkhd: &[u8]) -> Result<u32, ReddSaverError> {
+    read_u32(tkhd, track_id_position(tkhd)?)
+}
+
+/// Position and value of the duration in the payload of a `tkhd` box
+fn track_duration(tkhd: &[u8]) -> Result<(usize, u64), ReddSaverError> {
+    if tkhd.first() == Some(&1) {
+        Ok((28, read_u64(tkhd, 28)?))
+    } else {
+        Ok((20, read_u32(tkhd, 20)? as u64))
+    }
+}
+
+/// Payload of an `mehd` box with the duration of the whole fragmented movie
+fn patch_mehd(mehd: &Mp4Box, duration: u64) -> Result<Vec<u8>, ReddSaverError> {
+    patch_sized(mehd.payload(), 4, duration)
+}
+
+/// Payload of an `elst` box with the durations of its edits converted to another timescale
+fn scale_edits(elst: &[u8], from: u32, to: u32) -> Result<Vec<u8>, ReddSaverError> {
+    let wide = elst.first() == Some(&1);
+    let entry = if wide { 20 } else { 12 };
+    let count = read_u32(elst, 4)? as usize;
+    let mut payload = elst.to_vec();
+    for i in 0..count {
+        let position = 8 + i * entry;
+        payload = if wide {
+            patch_u64(
+                &payload,
+                position,
+                scale(read_u64(&payload, position)?, from, to),
+            )?
+        } else {
+            let duration = scale(read_u32(&payload, position)? as u64, from, to);
+            patch_u32(&payload, position, duration.min(u32::MAX as u64) as u32)?
+        };
+    }
+
+    Ok(payload)
+}
+
+/// Replace the 32 bit value at `position` for version 0 boxes, or the 64 bit one for version 1
+fn patch_sized(payload: &[u8], position: usize, value: u64) -> Result<Vec<u8>, ReddSaverError> {
+    if payload.first() == Some(&1) {
+        patch_u64(payload, position, value)
+    } else {
+        patch_u32(payload, position, value.min(u32::MAX as u64) as u32)
+    }
+}
+
+fn scale(value: u64, from: u32, to: u32) -> u64 {
+    (value as u128 * to as u128 / from.max(1) as u128) as u64
+}
+
+fn read_u32(data: &[u8], position: usize) -> Result<u32, ReddSaverError> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(
+        data.get(position..position + 4)
+            .ok_or_else(|| invalid("box too short"))?,
+    );
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(data: &[u8], position: usize) -> Result<u64, ReddSaverError> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(
+        data.get(position..position + 8)
+            .ok_or_else(|| invalid("box too short"))?,
+    );
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn patch_u32(data: &[u8], position: usize, value: u32) -> Result<Vec<u8>, ReddSaverError> {
+    read_u32(data, position)?;
+    let mut patched = data.to_vec();
+    patched[position..position + 4].copy_from_slice(&value.to_be_bytes());
+    Ok(patched)
+}
+
+fn patch_u64(data: &[u8], position: usize, value: u64) -> Result<Vec<u8>, ReddSaverError> {
+    read_u64(data, position)?;
+    let mut patched = data.to_vec();
+    patched[position..position + 8].copy_from_slice(&value.to_be_bytes());
+    Ok(patched)
+}
+
+fn invalid(reason: &str) -> ReddSaverError {
+    ReddSaverError::MuxFailed(String::from(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn merge(video: &[u8], audio: &[u8]) -> Result<Vec<u8>, ReddSaverError> {
+        let mut merged = Vec::new();
+        merge_mp4(
+            &mut Cursor::new(video),
+            &mut Cursor::new(audio),
+            &mut merged,
+        )?;
+        Ok(merged)
+    }
+
+    fn full_box(kind: &[u8; 4], fields: &[u32]) -> Vec<u8> {
+        let payload: Vec<u8> = fields
+            .iter()
+            .flat_map(|f| f.to_be_bytes().to_vec())
+            .collect();
+        write_box(kind, &payload, false)
+    }
+
+    fn container(kind: &[u8; 4], children: &[Vec<u8>]) -> Vec<u8> {
+        write_box(kind, &children.concat(), false)
+    }
+
+    /// Regular MP4 file with a single track whose only chunk is `data`
+    fn regular(track: u32, timescale: u32, duration: u32, data: &[u8]) -> Vec<u8> {
+        let ftyp = write_box(b"ftyp", b"isom\0\0\0\0", false);
+        let moov = |chunk: u32| {
+            container(
+                b"moov",
+                &[
+                    // version, times, timescale, duration, then the next track ID last
+                    full_box(b"mvhd", &[0, 0, 0, timescale, duration, track + 1]),
+                    container(
+                        b"trak",
+                        &[
+                            full_box(b"tkhd", &[0, 0, 0, track, 0, duration]),
+                            container(
+                                b"mdia",
+                                &[container(
+                                    b"minf",
+                                    &[container(b"stbl", &[full_box(b"stco", &[0, 1, chunk])])],
+                                )],
+                            ),
+                        ],
+                    ),
+                ],
+            )
+        };
+        let chunk = (ftyp.len() + moov(0).len() + 8) as u32;
+        [ftyp, moov(chunk), write_box(b"mdat", data, false)].concat()
+    }
+
+    /// Fragmented MP4 file with a single track and a single fragment holding `data`
+    fn fragmented(track: u32, data: &[u8]) -> Vec<u8> {
+        [
+            write_box(b"ftyp", b"iso6\0\0\0\0", false),
+            container(
+                b"moov",
+                &[
+                    full_box(b"mvhd", &[0, 0, 0, 1000, 0, track + 1]),
+                    container(b"trak", &[full_box(b"tkhd", &[0, 0, 0, track, 0, 0])]),
+                    container(b"mvex", &[full_box(b"trex", &[0, track, 1, 0, 0, 0])]),
+                ],
+            ),
+            container(
+                b"moof",
+                &[
+                    full_box(b"mfhd", &[0, 1]),
+                    container(b"traf", &[full_box(b"tfhd", &[0x0002_0000, track])]),
+                ],
+            ),
+            write_box(b"mdat", data, false),
+        ]
+        .concat()
+    }
+
+    fn all<'a>(b: &Mp4Box<'a>, kind: &[u8; 4], found: &mut Vec<Mp4Box<'a>>) {
+        for child in b.children().unwrap() {
+            if child.is(kind) {
+                found.push(child);
+            } else if CONTAINERS.contains(&&child.kind) {
+                all(&child, kind, found);
+            }
+        }
+    }
+
+    fn find_all<'a>(file: &'a [u8], kind: &[u8; 4]) -> Vec<Mp4Box<'a>> {
+        let mut found = Vec::new();
+        for b in parse_boxes(file, 0).unwrap() {
+            if b.is(kind) {
+                found.push(b);
+            } else if CONTAINERS.contains(&&b.kind) {
+                all(&b, kind, &mut found);
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn tracks_of_regular_files_are_merged() {
+        let video = regular(1, 1000, 5000, b"VIDEO");
+        let audio = regular(1, 48000, 240_000, b"AUDIO");
+
+        let merged = merge(&video, &audio).unwrap();
+
+        let ids: Vec<u32> = find_all(&merged, b"tkhd")
+            .iter()
+            .map(|b| track_id(b.payload()).unwrap())
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+        // the chunk offsets point to the media in the merged file
+        let chunks: Vec<&[u8]> = find_all(&merged, b"stco")
+            .iter()
+            .map(|b| {
+                let offset = read_u32(b.payload(), 8).unwrap() as usize;
+                &merged[offset..offset + 5]
+            })
+            .collect();
+        assert_eq!(chunks, vec![&b"VIDEO"[..], &b"AUDIO"[..]]);
+        // the audio durations are converted to the timescale of the movie
+        let audio_tkhd = &find_all(&merged, b"tkhd")[1];
+        assert_eq!(track_duration(audio_tkhd.payload()).unwrap().1, 5000);
+        let mvhd = &find_all(&merged, b"mvhd")[0];
+        assert_eq!(
+            read_u32(mvhd.payload(), mvhd.payload().len() - 4).unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn media_extending_to_the_end_of_the_file_are_given_their_size() {
+        let mut video = regular(1, 1000, 5000, b"VIDEO");
+        let mdat = video.len() - 13;
+        video[mdat..mdat + 4].copy_from_slice(&0u32.to_be_bytes());
+
+        let merged = merge(&video, &regular(1, 1000, 5000, b"AUDIO")).unwrap();
+
+        let mdats: Vec<&[u8]> = parse_boxes(&merged, 0)
+            .unwrap()
+            .into_iter()
+            .filter(|b| b.is(b"mdat"))
+            .map(|b| b.payload())
+            .collect();
+        assert_eq!(mdats, vec![&b"VIDEO"[..], &b"AUDIO"[..]]);
+    }
+
+    #[test]
+    fn tracks_of_fragmented_files_are_merged() {
+        let merged = merge(&fragmented(1, b"VIDEO"), &fragmented(1, b"AUDIO")).unwrap();
+
+        let fragments: Vec<(u32, u32)> = find_all(&merged, b"tfhd")
+            .iter()
+            .zip(find_all(&merged, b"mfhd").iter())
+            .map(|(tfhd, mfhd)| {
+                (
+                    read_u32(tfhd.payload(), 4).unwrap(),
+                    read_u32(mfhd.payload(), 4).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(fragments, vec![(1, 1), (2, 2)]);
+        let trex: Vec<u32> = find_all(&merged, b"trex")
+            .iter()
+            .map(|b| read_u32(b.payload(), 4).unwrap())
+            .collect();
+        assert_eq!(trex, vec![1, 2]);
+
+        assert!(merge(&fragmented(1, b"VIDEO"), &regular(1, 1000, 1, b"AUDIO")).is_err());
+    }
+}
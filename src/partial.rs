@@ -0,0 +1,267 @@
+use crate::errors::ReddSaverError;
+
+use log::{debug, warn};
+use reqwest::header::{HeaderMap, ACCEPT_RANGES, CONTENT_RANGE, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{fs, io};
+use tokio::fs::File;
+
+/// Extension of the files media are written to until they have been downloaded completely
+pub static PART_EXTENSION: &str = "part";
+/// Extension of the files next to partial downloads that hold what is needed to resume them
+pub static RESUME_EXTENSION: &str = "resume";
+/// How much older than the current run a partial download has to be to be considered stale
+static STALE_MARGIN: Duration = Duration::from_secs(1);
+
+/// Path of the file the media saved to `file_name` is written to while downloading
+pub fn part_path(file_name: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}", file_name, PART_EXTENSION))
+}
+
+/// Path of the file describing the partial download of the media saved to `file_name`
+pub fn resume_path(file_name: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}", file_name, RESUME_EXTENSION))
+}
+
+/// What is known about the remote file a partial download was started from, to make sure
+/// that the rest of the download belongs to the same file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Validator {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validator {
+    /// Validator of a response, if the server allows resuming its download with range requests.
+    /// Weak ETags cannot be used in `If-Range`, so they are ignored
+    pub fn from_response(url: &str, headers: &HeaderMap) -> Option<Self> {
+        let ranges = header(headers, ACCEPT_RANGES.as_str())?;
+        if !ranges.eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+
+        let etag = header(headers, ETAG.as_str()).filter(|e| !e.starts_with("W/"));
+        let last_modified = header(headers, LAST_MODIFIED.as_str());
+        if etag.is_none() && last_modified.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            url: String::from(url),
+            etag,
+            last_modified,
+        })
+    }
+
+    /// Value of the `If-Range` header, which makes the server send the whole file instead of
+    /// the requested range if the file has changed
+    pub fn if_range(&self) -> &str {
+        self.etag
+            .as_deref()
+            .or(self.last_modified.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Whether a partial response is for the same file, in case the server ignored `If-Range`
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        let same = |stored: &Option<String>, name: &str| match (stored, header(headers, name)) {
+            (Some(stored), Some(received)) => *stored == received,
+            _ => true,
+        };
+
+        same(&self.etag, ETAG.as_str()) && same(&self.last_modified, LAST_MODIFIED.as_str())
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+/// First byte of the range sent in a partial response, e.g. 100 for `bytes 100-999/1000`
+pub fn range_start(headers: &HeaderMap) -> Option<u64> {
+    let range = header(headers, CONTENT_RANGE.as_str())?;
+    let range = range.trim().strip_prefix("bytes ")?;
+    range.split('-').next()?.trim().parse().ok()
+}
+
+/// Validator saved for the partial download of `file_name`, if it was started from `url`
+pub async fn load_validator(file_name: &str, url: &str) -> Option<Validator> {
+    let contents = tokio::fs::read(resume_path(file_name)).await.ok()?;
+    serde_json::from_slice::<Validator>(&contents)
+        .ok()
+        .filter(|v| v.url == url)
+}
+
+/// Save the validator of a download, so that it can be resumed if it is interrupted
+pub async fn save_validator(file_name: &str, validator: &Validator) -> Result<(), ReddSaverError> {
+    let contents = serde_json::to_vec(validator).map_err(io::Error::from)?;
+    tokio::fs::write(resume_path(file_name), contents).await?;
+
+    Ok(())
+}
+
+/// Remove the validator of a download that is complete or cannot be resumed
+pub async fn remove_validator(file_name: &str) {
+    let path = resume_path(file_name);
+    match tokio::fs::remove_file(&path).await {
+        Ok(_) => debug!("Removed {}", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => warn!("Could not remove {}: {}", path.display(), e),
+    }
+}
+
+/// Flush the media downloaded for `file_name` to disk and move it to its final name `saved`.
+/// The rename is atomic, so the final file either does not exist or holds the complete media,
+/// even after a crash
+pub async fn commit(
+    mut file: File,
+    part: &Path,
+    file_name: &str,
+    saved: &str,
+) -> Result<(), ReddSaverError> {
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(part, saved).await?;
+    sync_directory(Path::new(saved).parent()).await;
+    remove_validator(file_name).await;
+
+    Ok(())
+}
+
+/// Persist the rename on file systems that need the directory to be synced as well
+#[cfg(unix)]
+async fn sync_directory(directory: Option<&Path>) {
+    if let Some(d) = directory {
+        let result = match File::open(d).await {
+            Ok(mut dir) => dir.sync_all().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            debug!("Could not sync directory {}: {}", d.display(), e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn sync_directory(_directory: Option<&Path>) {}
+
+/// Remove the partial downloads below `directory` that were last written to before
+/// `started`, i.e. left behind by an earlier run and not resumed since. Only those of the
+/// media in `handled`, given as file names without extension, are removed, as the others
+/// may belong to a run for another account saving to the same directory
+pub async fn remove_stale(
+    directory: &str,
+    started: SystemTime,
+    handled: HashSet<PathBuf>,
+) -> Result<usize, ReddSaverError> {
+    let directory = PathBuf::from(directory);
+    // file systems record modification times with a coarser clock, so a file written right
+    // after the run started may look older than it is
+    let started = started.checked_sub(STALE_MARGIN).unwrap_or(started);
+    let removed =
+        tokio::task::spawn_blocking(move || remove_stale_below(&directory, started, &handled))
+            .await??;
+
+    Ok(removed)
+}
+
+fn remove_stale_below(
+    directory: &Path,
+    started: SystemTime,
+    handled: &HashSet<PathBuf>,
+) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+
+        if metadata.is_dir() {
+            removed += remove_stale_below(&path, started, handled)?;
+        } else if is_partial(&path) && is_handled(&path, handled) && metadata.modified()? < started
+        {
+            match fs::remove_file(&path) {
+                Ok(_) => {
+                    debug!("Removed stale partial download {}", path.display());
+                    removed += 1;
+                }
+                Err(e) => warn!("Could not remove {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Whether a partial download is of one of the media in `handled`. It is named after the
+/// file name of the media, which may have an extension or the name of a track of a video
+fn is_handled(partial: &Path, handled: &HashSet<PathBuf>) -> bool {
+    let media = partial.with_extension("");
+    handled.contains(&media) || handled.contains(&media.with_extension(""))
+}
+
+fn is_partial(path: &Path) -> bool {
+    let extension = path.extension();
+    extension == Some(OsStr::new(PART_EXTENSION)) || extension == Some(OsStr::new(RESUME_EXTENSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn only_strongly_validated_ranges_can_be_resumed() {
+        let url = "https://i.redd.it/abc.jpg";
+        let strong = headers(&[("accept-ranges", "bytes"), ("etag", "\"v1\"")]);
+        assert_eq!(
+            Validator::from_response(url, &strong).unwrap().if_range(),
+            "\"v1\""
+        );
+
+        let weak = headers(&[("accept-ranges", "bytes"), ("etag", "W/\"v1\"")]);
+        assert!(Validator::from_response(url, &weak).is_none());
+
+        let no_ranges = headers(&[("accept-ranges", "none"), ("etag", "\"v1\"")]);
+        assert!(Validator::from_response(url, &no_ranges).is_none());
+
+        let dated = headers(&[
+            ("accept-ranges", "bytes"),
+            ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ]);
+        let validator = Validator::from_response(url, &dated).unwrap();
+        assert_eq!(validator.if_range(), "Wed, 21 Oct 2015 07:28:00 GMT");
+        assert!(validator.matches(&dated));
+        assert!(!validator.matches(&headers(&[(
+            "last-modified",
+            "Thu, 22 Oct 2015 07:28:00 GMT"
+        )])));
+    }
+
+    #[test]
+    fn start_of_partial_response_is_read_from_content_range() {
+        let partial = headers(&[("content-range", "bytes 100-999/1000")]);
+        assert_eq!(range_start(&partial), Some(100));
+        assert_eq!(
+            range_start(&headers(&[("content-range", "bytes */1000")])),
+            None
+        );
+        assert_eq!(range_start(&HeaderMap::new()), None);
+    }
+}
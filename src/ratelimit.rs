@@ -0,0 +1,154 @@
+use log::{debug, warn};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::delay_for;
+
+static REMAINING_HEADER: &str = "x-ratelimit-remaining";
+static USED_HEADER: &str = "x-ratelimit-used";
+static RESET_HEADER: &str = "x-ratelimit-reset";
+/// Number of seconds to wait after the first HTTP 429 without any hint from Reddit,
+/// doubled on every further attempt
+static BASE_BACKOFF: u64 = 2;
+/// Longest we are willing to back off for a single HTTP 429
+static MAX_BACKOFF: u64 = 600;
+
+/// Rate limit budget as last reported by Reddit
+#[derive(Debug, Default)]
+struct Budget {
+    /// Requests left in the current window
+    remaining: Option<f64>,
+    /// When the current window ends and the budget is refilled
+    reset_at: Option<Instant>,
+}
+
+/// Rate limiter shared by all API calls of a session. Reddit tracks the budget per
+/// OAuth client and user, and reports it in the `X-Ratelimit-*` headers of every response
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    budget: Mutex<Budget>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait until the budget allows another request and reserve it
+    pub async fn acquire(&self) {
+        // the lock is held while sleeping, so that all requests queue up behind the one waiting
+        let mut budget = self.budget.lock().await;
+
+        if let (Some(remaining), Some(reset_at)) = (budget.remaining, budget.reset_at) {
+            let now = Instant::now();
+            if remaining < 1.0 && reset_at > now {
+                let wait = reset_at - now;
+                warn!(
+                    "Reddit rate limit exhausted, waiting {} seconds for it to reset",
+                    wait.as_secs()
+                );
+                delay_for(wait).await;
+            }
+            if reset_at <= Instant::now() {
+                *budget = Budget::default();
+            }
+        }
+
+        if let Some(remaining) = budget.remaining.as_mut() {
+            *remaining -= 1.0;
+        }
+    }
+
+    /// Update the budget from the headers of a response
+    pub async fn update(&self, headers: &HeaderMap) {
+        if let Some((remaining, used, reset)) = parse_headers(headers) {
+            debug!(
+                "Rate limit: {} requests used, {} remaining, reset in {} seconds",
+                used, remaining, reset
+            );
+            let mut budget = self.budget.lock().await;
+            budget.remaining = Some(remaining);
+            budget.reset_at = Some(Instant::now() + Duration::from_secs(reset));
+        }
+    }
+
+    /// Record an HTTP 429 response and hold back every request until the time given by
+    /// Reddit has passed, backing off exponentially if it did not say how long to wait
+    pub async fn throttle(&self, headers: &HeaderMap, attempt: u32) {
+        let wait = backoff(headers, attempt);
+        warn!(
+            "Reddit responded with HTTP 429, backing off for {} seconds",
+            wait.as_secs()
+        );
+
+        let mut budget = self.budget.lock().await;
+        budget.remaining = Some(0.0);
+        budget.reset_at = Some(Instant::now() + wait);
+    }
+}
+
+/// Read the remaining, used and reset values of the `X-Ratelimit-*` headers
+fn parse_headers(headers: &HeaderMap) -> Option<(f64, f64, u64)> {
+    let value = |name: &str| headers.get(name)?.to_str().ok()?.trim().parse::<f64>().ok();
+
+    let remaining = value(REMAINING_HEADER)?;
+    let used = value(USED_HEADER).unwrap_or_default();
+    let reset = value(RESET_HEADER)?;
+
+    Some((remaining, used, reset.max(0.0).ceil() as u64))
+}
+
+/// Time to wait after the `attempt`-th HTTP 429 in a row, preferring `Retry-After`
+/// and the rate limit reset over our own exponential backoff
+fn backoff(headers: &HeaderMap, attempt: u32) -> Duration {
+    let retry_after = headers
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    let reset = parse_headers(headers).map(|(_, _, reset)| reset);
+
+    let seconds = match retry_after.or(reset) {
+        Some(s) if s > 0 => s,
+        _ => BASE_BACKOFF.saturating_mul(2u64.saturating_pow(attempt)),
+    };
+
+    Duration::from_secs(seconds.min(MAX_BACKOFF))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(*name, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    #[test]
+    fn reddit_headers_are_parsed() {
+        let map = headers(&[
+            ("x-ratelimit-remaining", "598.0"),
+            ("x-ratelimit-used", "2"),
+            ("x-ratelimit-reset", "412"),
+        ]);
+        assert_eq!(parse_headers(&map), Some((598.0, 2.0, 412)));
+        assert_eq!(parse_headers(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn backoff_prefers_server_hints() {
+        let map = headers(&[("retry-after", "7"), ("x-ratelimit-remaining", "0")]);
+        assert_eq!(backoff(&map, 3), Duration::from_secs(7));
+
+        let map = headers(&[("x-ratelimit-remaining", "0"), ("x-ratelimit-reset", "42")]);
+        assert_eq!(backoff(&map, 0), Duration::from_secs(42));
+
+        assert_eq!(backoff(&HeaderMap::new(), 0), Duration::from_secs(2));
+        assert_eq!(backoff(&HeaderMap::new(), 2), Duration::from_secs(8));
+        assert_eq!(backoff(&HeaderMap::new(), 20), Duration::from_secs(600));
+    }
+}
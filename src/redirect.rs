@@ -0,0 +1,107 @@
+use crate::errors::ReddSaverError;
+
+use log::{debug, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+static SUCCESS_PAGE: &str =
+    "Authorization complete. You can close this tab and return to reddsaver.";
+static FAILURE_PAGE: &str = "Authorization failed. Check the reddsaver logs for details.";
+
+/// Listen on the redirect URI registered for the application and wait for Reddit to send
+/// the browser back with the authorization code
+pub async fn wait_for_code(redirect_uri: &str, state: &str) -> Result<String, ReddSaverError> {
+    let redirect = Url::parse(redirect_uri)?;
+    let host = redirect.host_str().unwrap_or("localhost");
+    let port = redirect.port_or_known_default().unwrap_or(80);
+
+    let mut listener = TcpListener::bind(format!("{}:{}", host, port)).await?;
+    info!("Waiting for the authorization redirect on {}", redirect_uri);
+
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        debug!("Accepted redirect connection from {}", peer);
+
+        let target = match read_request_target(&mut stream).await? {
+            Some(t) => t,
+            None => continue,
+        };
+        // the request line only contains the path and the query, so join it with the
+        // redirect URI to be able to use the URL parser on it
+        let url = redirect.join(&target)?;
+
+        // browsers like to ask for a favicon as well, ignore anything not sent to the redirect path
+        if url.path() != redirect.path() {
+            respond(&mut stream, "404 Not Found", "").await?;
+            continue;
+        }
+
+        let param = |key: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.into_owned())
+        };
+
+        if let Some(error) = param("error") {
+            respond(&mut stream, "400 Bad Request", FAILURE_PAGE).await?;
+            return Err(ReddSaverError::AuthorizationDenied(error));
+        }
+
+        if param("state").as_deref() != Some(state) {
+            respond(&mut stream, "400 Bad Request", FAILURE_PAGE).await?;
+            return Err(ReddSaverError::AuthorizationDenied(String::from(
+                "state mismatch",
+            )));
+        }
+
+        return match param("code") {
+            Some(code) => {
+                respond(&mut stream, "200 OK", SUCCESS_PAGE).await?;
+                Ok(code)
+            }
+            None => {
+                respond(&mut stream, "400 Bad Request", FAILURE_PAGE).await?;
+                Err(ReddSaverError::AuthorizationDenied(String::from(
+                    "no code in redirect",
+                )))
+            }
+        };
+    }
+}
+
+/// Read the HTTP request headers and return the request target from the request line
+async fn read_request_target(stream: &mut TcpStream) -> Result<Option<String>, ReddSaverError> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    // the redirect is a simple GET request, so we only need to read until the end of the headers
+    while !buffer.windows(4).any(|w| w == b"\r\n\r\n") && buffer.len() < 16 * 1024 {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&buffer);
+    let target = request
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .map(String::from);
+
+    Ok(target)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), ReddSaverError> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use crate::errors::ReddSaverError;
+
+use log::warn;
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Response, StatusCode};
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+use tokio::time::delay_for;
+
+/// Default number of times a media download or media host API call is attempted
+pub static DEFAULT_RETRY_ATTEMPTS: u32 = 4;
+/// Default number of milliseconds to wait before the first retry, doubled on every further one
+pub static DEFAULT_RETRY_DELAY: u64 = 500;
+/// Longest we are willing to wait before a single retry, whatever the server asks for
+static MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How often and how long to wait before trying again when a request to a media host fails
+/// for a reason that may go away on its own, such as a timeout or an overloaded server
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of attempts in total, including the first one
+    pub attempts: u32,
+    /// Time to wait before the first retry
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_RETRY_ATTEMPTS,
+            delay: Duration::from_millis(DEFAULT_RETRY_DELAY),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Run the operation until it succeeds, fails permanently or runs out of attempts,
+    /// returning the outcome of the last attempt
+    pub async fn run<T, F, R>(&self, what: &str, mut operation: F) -> Result<T, ReddSaverError>
+    where
+        F: FnMut() -> R,
+        R: Future<Output = Result<T, ReddSaverError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match operation().await {
+                Err(e) if attempt + 1 < self.attempts && is_retryable(&e) => {
+                    let wait = self.wait(attempt, &e);
+                    warn!(
+                        "{} failed: {}. Retrying in {:.1} seconds ({}/{})",
+                        what,
+                        e,
+                        wait.as_secs_f64(),
+                        attempt + 1,
+                        self.attempts - 1
+                    );
+                    delay_for(wait).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Time to wait before the retry following the `attempt`-th failure, preferring the
+    /// `Retry-After` sent by the server over our own exponential backoff
+    fn wait(&self, attempt: u32, error: &ReddSaverError) -> Duration {
+        let wait = match error {
+            ReddSaverError::HttpStatus(_, _, Some(retry_after)) => *retry_after,
+            _ => {
+                // add up to half of the delay at random, so that downloads failing at the
+                // same time do not all hit the host again at the same time. The delay is
+                // capped first, as a long configured delay would overflow once doubled
+                let delay = self
+                    .delay
+                    .checked_mul(2u32.saturating_pow(attempt.min(16)))
+                    .unwrap_or(MAX_RETRY_DELAY)
+                    .min(MAX_RETRY_DELAY);
+                let jitter = rand::thread_rng().gen_range(0.0, 0.5);
+                delay.mul_f64(1.0 + jitter)
+            }
+        };
+
+        wait.min(MAX_RETRY_DELAY)
+    }
+}
+
+/// Turn a response with an error status into an error, remembering how long the server
+/// asked to wait before trying again
+pub fn error_for_status(response: Response) -> Result<Response, ReddSaverError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    Err(ReddSaverError::HttpStatus(
+        response.url().to_string(),
+        status.as_u16(),
+        retry_after(response.headers(), SystemTime::now()),
+    ))
+}
+
+/// Whether the request may succeed if it is made again. Timeouts, dropped connections,
+/// server errors and rate limiting usually pass, while missing media stay missing
+pub fn is_retryable(error: &ReddSaverError) -> bool {
+    match error {
+        ReddSaverError::TimedOut => true,
+        ReddSaverError::ReqwestError(e) => {
+            e.is_timeout() || e.is_connect() || e.is_request() || e.is_body()
+        }
+        ReddSaverError::HttpStatus(_, status, _) => match StatusCode::from_u16(*status) {
+            Ok(s) => {
+                s.is_server_error()
+                    || s == StatusCode::REQUEST_TIMEOUT
+                    || s == StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether the media is gone for good, e.g. because it was deleted, so that there is no point
+/// in trying to download it again in a later run
+pub fn is_dead(error: &ReddSaverError) -> bool {
+    match error {
+        ReddSaverError::MediaGone(..) => true,
+        ReddSaverError::HttpStatus(_, status, _) => {
+            *status == StatusCode::NOT_FOUND.as_u16() || *status == StatusCode::GONE.as_u16()
+        }
+        _ => false,
+    }
+}
+
+/// Time to wait as given by the `Retry-After` header, either in seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(date.duration_since(now).unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn status(code: u16) -> ReddSaverError {
+        ReddSaverError::HttpStatus(String::from("https://i.redd.it/abc.jpg"), code, None)
+    }
+
+    #[test]
+    fn only_transient_failures_are_retried() {
+        assert!(is_retryable(&ReddSaverError::TimedOut));
+        assert!(is_retryable(&status(503)));
+        assert!(is_retryable(&status(429)));
+        assert!(!is_retryable(&status(404)));
+        assert!(!is_retryable(&status(410)));
+        assert!(!is_retryable(&ReddSaverError::CouldNotCreateDirectory));
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_date() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("12"));
+        let now = SystemTime::now();
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(12)));
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:27:30 GMT").unwrap();
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_within_bounds() {
+        let policy = RetryPolicy {
+            attempts: 10,
+            delay: Duration::from_secs(1),
+        };
+        let error = ReddSaverError::TimedOut;
+        let first = policy.wait(0, &error);
+        assert!(first >= Duration::from_secs(1) && first <= Duration::from_millis(1500));
+        let third = policy.wait(2, &error);
+        assert!(third >= Duration::from_secs(4) && third <= Duration::from_secs(6));
+        assert_eq!(policy.wait(9, &error), MAX_RETRY_DELAY);
+
+        let long = RetryPolicy {
+            attempts: u32::MAX,
+            delay: Duration::from_millis(u64::MAX),
+        };
+        assert_eq!(long.wait(0, &error), MAX_RETRY_DELAY);
+        assert_eq!(long.wait(u32::MAX - 1, &error), MAX_RETRY_DELAY);
+
+        let limited = ReddSaverError::HttpStatus(String::new(), 429, Some(Duration::from_secs(7)));
+        assert_eq!(policy.wait(0, &limited), Duration::from_secs(7));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::errors::ReddSaverError;
+
+use log::debug;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::process::Command;
+
+/// Where the value of a secret was read from
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecretSource {
+    /// Given directly, e.g. as `PASSWORD`
+    Value(String),
+    /// Read from the file named by e.g. `PASSWORD_FILE`
+    File(String, String),
+    /// Printed by the command in e.g. `PASSWORD_COMMAND`
+    Command(String),
+    /// Piped in through `--password-stdin`
+    Stdin,
+}
+
+impl fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretSource::Value(origin) => write!(f, "{}", origin),
+            SecretSource::File(origin, path) => write!(f, "{} ({})", origin, path),
+            SecretSource::Command(origin) => write!(f, "{}", origin),
+            SecretSource::Stdin => write!(f, "stdin"),
+        }
+    }
+}
+
+/// A resolved secret along with the place it came from
+#[derive(Debug, Clone)]
+pub struct Secret {
+    pub value: String,
+    pub source: SecretSource,
+}
+
+/// The different ways a single secret can be provided. When more than one is set,
+/// the file takes precedence over the command, which takes precedence over the plain value
+#[derive(Debug)]
+pub struct SecretSpec {
+    /// Name of the secret, used to describe where the value came from
+    name: String,
+    value: Option<String>,
+    file: Option<String>,
+    command: Option<String>,
+}
+
+impl SecretSpec {
+    pub fn new(
+        name: &str,
+        value: Option<String>,
+        file: Option<String>,
+        command: Option<String>,
+    ) -> Self {
+        Self {
+            name: String::from(name),
+            value,
+            file,
+            command,
+        }
+    }
+
+    /// Look up `NAME`, `NAME_FILE` and `NAME_COMMAND` in the environment
+    pub fn from_env(name: &str) -> Self {
+        Self::new(
+            name,
+            env::var(name).ok(),
+            env::var(format!("{}_FILE", name)).ok(),
+            env::var(format!("{}_COMMAND", name)).ok(),
+        )
+    }
+
+    /// Name of the variant of the secret with the given suffix, matching the case of the
+    /// name: `PASSWORD_FILE` for environment variables, `password_file` in configuration files
+    fn variant(&self, suffix: &str) -> String {
+        if self.name.chars().any(|c| c.is_ascii_lowercase()) {
+            format!("{}_{}", self.name, suffix.to_lowercase())
+        } else {
+            format!("{}_{}", self.name, suffix)
+        }
+    }
+
+    /// Read the secret from the highest precedence source that is set
+    pub fn resolve(&self) -> Result<Option<Secret>, ReddSaverError> {
+        if let Some(path) = &self.file {
+            debug!("Reading {} from file {}", self.name, path);
+            let contents = fs::read_to_string(path)
+                .map_err(|_| ReddSaverError::CouldNotReadSecret(self.name.clone()))?;
+
+            return Ok(Some(Secret {
+                value: trim_newline(contents),
+                source: SecretSource::File(self.variant("FILE"), path.clone()),
+            }));
+        }
+
+        if let Some(command) = &self.command {
+            debug!("Reading {} from command", self.name);
+            return Ok(Some(Secret {
+                value: run_command(&self.name, command)?,
+                source: SecretSource::Command(self.variant("COMMAND")),
+            }));
+        }
+
+        Ok(self.value.as_ref().map(|v| Secret {
+            value: v.clone(),
+            source: SecretSource::Value(self.name.clone()),
+        }))
+    }
+
+    /// Same as `resolve`, but fail if none of the sources is set
+    pub fn require(&self) -> Result<Secret, ReddSaverError> {
+        self.resolve()?
+            .ok_or_else(|| ReddSaverError::MissingSecret(self.name.clone()))
+    }
+}
+
+/// Read a secret piped into the standard input
+pub fn from_stdin() -> Result<Secret, ReddSaverError> {
+    let mut contents = String::new();
+    io::stdin().read_to_string(&mut contents)?;
+
+    Ok(Secret {
+        value: trim_newline(contents),
+        source: SecretSource::Stdin,
+    })
+}
+
+/// Run the command through the shell and use whatever it prints as the secret
+fn run_command(name: &str, command: &str) -> Result<String, ReddSaverError> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").args(&["/C", command]).output()
+    } else {
+        Command::new("sh").args(&["-c", command]).output()
+    }
+    .map_err(|_| ReddSaverError::CouldNotReadSecret(String::from(name)))?;
+
+    if !output.status.success() {
+        return Err(ReddSaverError::CouldNotReadSecret(String::from(name)));
+    }
+
+    Ok(trim_newline(
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+    ))
+}
+
+/// Files and commands usually end with a newline which is not part of the secret
+fn trim_newline(mut value: String) -> String {
+    while value.ends_with('\n') || value.ends_with('\r') {
+        value.pop();
+    }
+    value
+}
@@ -1,5 +1,7 @@
+use log::info;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::ops::Add;
 
 /// Data structure that represents a user's info
@@ -109,6 +111,8 @@ pub struct PostData {
     pub created_utc: Value,
     /// Gallery metadata
     pub gallery_data: Option<GalleryItems>,
+    /// Details of the media uploaded to Reddit for the post, such as gallery items, by media id
+    pub media_metadata: Option<HashMap<String, MediaMetadata>>,
     /// Is post a video?
     pub is_video: Option<bool>,
     /// Reddit Media info
@@ -123,6 +127,10 @@ pub struct PostMedia {
 #[derive(Deserialize, Debug, Clone)]
 pub struct RedditVideo {
     pub fallback_url: String,
+    /// DASH manifest listing the video and audio streams, which the fallback lacks the audio of
+    pub dash_url: Option<String>,
+    /// HLS master playlist of the same streams, read when there is no DASH manifest
+    pub hls_url: Option<String>,
     pub is_gif: bool,
 }
 
@@ -138,6 +146,32 @@ pub struct GalleryItem {
     pub media_id: String,
     /// Unique numerical ID for the specific media item
     pub id: i64,
+    /// Caption of the item, if the poster gave it one
+    pub caption: Option<String>,
+    /// Link the poster attached to the item
+    pub outbound_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MediaMetadata {
+    /// `valid` once Reddit processed the upload, `failed` if it could not
+    pub status: Option<String>,
+    /// MIME type of the upload, e.g. `image/png`
+    #[serde(rename = "m")]
+    pub mime_type: Option<String>,
+    /// The media in its original size
+    #[serde(rename = "s")]
+    pub source: Option<MediaSource>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MediaSource {
+    /// URL of an image
+    #[serde(rename = "u")]
+    pub url: Option<String>,
+    /// URLs of an animated image, as a GIF or converted to MP4
+    pub gif: Option<String>,
+    pub mp4: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -154,7 +188,7 @@ pub struct GfyItem {
     pub mp4_url: String,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
 pub struct Summary {
     /// Number of media downloaded
     pub media_downloaded: i32,
@@ -162,6 +196,10 @@ pub struct Summary {
     pub media_skipped: i32,
     /// Number of media supported present and parsable
     pub media_supported: i32,
+    /// Number of media that could not be found or downloaded
+    pub media_failed: i32,
+    /// Number of media that were removed from their host and will not be retried
+    pub media_dead: i32,
 }
 
 impl Add for Summary {
@@ -172,6 +210,22 @@ impl Add for Summary {
             media_supported: self.media_supported + rhs.media_supported,
             media_downloaded: self.media_downloaded + rhs.media_downloaded,
             media_skipped: self.media_skipped + rhs.media_skipped,
+            media_failed: self.media_failed + rhs.media_failed,
+            media_dead: self.media_dead + rhs.media_dead,
         }
     }
 }
+
+impl Summary {
+    /// Print the summary statistics under the given heading
+    pub fn log(&self, heading: &str) {
+        info!("#####################################");
+        info!("{}:", heading);
+        info!("Number of supported media: {}", self.media_supported);
+        info!("Number of media downloaded: {}", self.media_downloaded);
+        info!("Number of media skipped: {}", self.media_skipped);
+        info!("Number of media failed: {}", self.media_failed);
+        info!("Number of media gone: {}", self.media_dead);
+        info!("#####################################");
+    }
+}
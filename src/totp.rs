@@ -0,0 +1,133 @@
+use crate::errors::ReddSaverError;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+
+/// Length of a time step in seconds, as used by Reddit and most authenticator apps
+static TIME_STEP: u64 = 30;
+/// Number of digits in the generated code
+static DIGITS: u32 = 6;
+
+/// Time-based one-time password generator as described in RFC 6238
+pub struct Totp {
+    secret: Vec<u8>,
+    step: u64,
+    digits: u32,
+}
+
+impl Totp {
+    /// Create a generator from the base32 encoded seed shown when setting up 2FA
+    pub fn from_base32(seed: &str) -> Result<Self, ReddSaverError> {
+        // authenticator apps display the seed in groups and in lower case, so normalise it first
+        let normalised: String = seed
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-' && *c != '=')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+
+        match base32::decode(base32::Alphabet::RFC4648 { padding: false }, &normalised) {
+            Some(secret) if !secret.is_empty() => Ok(Self::new(secret, TIME_STEP, DIGITS)),
+            _ => Err(ReddSaverError::InvalidTotpSecret),
+        }
+    }
+
+    fn new(secret: Vec<u8>, step: u64, digits: u32) -> Self {
+        Self {
+            secret,
+            step,
+            digits,
+        }
+    }
+
+    /// Generate the code for the time step containing `timestamp` (seconds since the UNIX epoch)
+    pub fn generate(&self, timestamp: u64) -> String {
+        let counter = timestamp / self.step;
+
+        let mut mac =
+            Hmac::<Sha1>::new_varkey(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(&counter.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // dynamic truncation from RFC 4226, section 5.3
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        let code = binary % 10u32.pow(self.digits);
+
+        format!("{:0width$}", code, width = self.digits as usize)
+    }
+
+    /// Generate the codes to try for `timestamp`: the current time step first, followed by the
+    /// previous and the next one to tolerate a small clock skew between us and Reddit
+    pub fn codes_around(&self, timestamp: u64) -> Vec<String> {
+        vec![
+            self.generate(timestamp),
+            self.generate(timestamp.saturating_sub(self.step)),
+            self.generate(timestamp + self.step),
+        ]
+    }
+
+    /// Number of seconds before the code for `timestamp` rolls over
+    pub fn seconds_remaining(&self, timestamp: u64) -> u64 {
+        self.step - timestamp % self.step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ASCII "12345678901234567890", the SHA1 seed used by the RFC 6238 test vectors
+    static RFC_SEED: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn rfc_totp() -> Totp {
+        let secret = Totp::from_base32(RFC_SEED).unwrap().secret;
+        Totp::new(secret, 30, 8)
+    }
+
+    #[test]
+    fn rfc_6238_test_vectors() {
+        let totp = rfc_totp();
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+
+        for (timestamp, expected) in vectors.iter() {
+            assert_eq!(totp.generate(*timestamp), *expected, "at {}", timestamp);
+        }
+    }
+
+    #[test]
+    fn six_digit_codes_are_truncated_and_padded() {
+        let totp = Totp::from_base32(RFC_SEED).unwrap();
+        assert_eq!(totp.generate(59), "287082");
+        assert_eq!(totp.generate(1111111109), "081804");
+    }
+
+    #[test]
+    fn seed_is_normalised_before_decoding() {
+        let totp = Totp::from_base32("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap();
+        assert_eq!(totp.generate(59), "287082");
+        assert!(Totp::from_base32("not base32!").is_err());
+        assert!(Totp::from_base32("").is_err());
+    }
+
+    #[test]
+    fn adjacent_steps_are_tried_for_clock_skew() {
+        let totp = Totp::from_base32(RFC_SEED).unwrap();
+        let codes = totp.codes_around(1111111111);
+        assert_eq!(codes[0], totp.generate(1111111111));
+        assert_eq!(codes[1], totp.generate(1111111081));
+        assert_eq!(codes[2], totp.generate(1111111141));
+        assert_eq!(totp.seconds_remaining(1111111111), 29);
+    }
+}
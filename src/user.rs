@@ -1,37 +1,50 @@
-use crate::auth::Auth;
+use crate::auth::Session;
 use crate::errors::ReddSaverError;
-use crate::structures::{UserAbout, UserSaved};
-use crate::utils::get_user_agent_string;
+use crate::http::Http;
+use crate::retry::error_for_status;
+use crate::structures::{Post, UserAbout, UserSaved};
+use futures::stream::{self, Stream, TryStreamExt};
 use log::{debug, info};
-use reqwest::header::USER_AGENT;
-use std::borrow::Borrow;
 use std::collections::HashMap;
 
 #[derive(Debug)]
 pub struct User<'a> {
-    /// Contains authentication information about the user
-    auth: &'a Auth,
+    /// Session holding the access token used for all API calls made on behalf of the user
+    session: &'a Session<'a>,
     /// Username of the user who authorized the application
     name: &'a str,
 }
 
 impl<'a> User<'a> {
-    pub fn new(auth: &'a Auth, name: &'a str) -> Self {
-        User { auth, name }
+    pub fn new(session: &'a Session<'a>, name: &'a str) -> Self {
+        User { session, name }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    /// User agent string to use for any other request made on behalf of the user
+    pub fn user_agent(&self) -> &str {
+        self.session.user_agent()
+    }
+
+    /// Shared HTTP client to use for any other request made on behalf of the user
+    pub fn http(&self) -> &Http {
+        self.session.http()
     }
 
     pub async fn about(&self) -> Result<UserAbout, ReddSaverError> {
         // all API requests that use a bearer token should be made to oauth.reddit.com instead
-        let url = format!("https://oauth.reddit.com/user/{}/about", self.name);
-        let client = reqwest::Client::new();
-
-        let response = client
-            .get(&url)
-            .bearer_auth(&self.auth.access_token)
-            // reddit will forbid you from accessing the API if the provided user agent is not unique
-            .header(USER_AGENT, get_user_agent_string(None, None))
-            .send()
-            .await?
+        let url = self
+            .http()
+            .endpoints()
+            .oauth(&format!("user/{}/about", self.name));
+        let response = self
+            .session
+            .execute(|| self.http().get(&url))
+            .await
+            .and_then(error_for_status)?
             .json::<UserAbout>()
             .await?;
 
@@ -40,76 +53,103 @@ impl<'a> User<'a> {
         Ok(response)
     }
 
-    pub async fn saved(&self) -> Result<Vec<UserSaved>, ReddSaverError> {
-        let client = reqwest::Client::new();
-
-        let mut complete = false;
-        let mut processed = 0;
-        let mut after: Option<String> = None;
-        let mut saved: Vec<UserSaved> = Vec::new();
-        while !complete {
-            // during the first call to the API, we would not provide the after query parameter
-            // in subsequent calls, we use the value for after from the response of the
-            //  previous request and continue doing so till the value of after is null
-            let url = if processed == 0 {
-                format!("https://oauth.reddit.com/user/{}/saved", self.name)
-            } else {
-                format!(
-                    "https://oauth.reddit.com/user/{}/saved?after={}",
-                    self.name,
-                    after.as_ref().unwrap()
-                )
+    /// Stream the saved posts of the user. The listing is fetched one page at a time, and the
+    /// next page is only requested once the posts of the previous one have been handed out
+    pub fn saved(&self) -> impl Stream<Item = Result<Post, ReddSaverError>> + '_ {
+        // the state is the number of items processed so far and the name of the item the next
+        // page starts after, or None once the last page has been fetched
+        stream::try_unfold((0, Some(None)), move |(processed, after)| async move {
+            let after: Option<String> = match after {
+                Some(a) => a,
+                None => return Ok::<_, ReddSaverError>(None),
             };
-
-            let response = client
-                .get(&url)
-                .bearer_auth(&self.auth.access_token)
-                .header(USER_AGENT, get_user_agent_string(None, None))
-                // the maximum number of items returned by the API in a single request is 100
-                .query(&[("limit", 100)])
-                .send()
-                .await?
-                .json::<UserSaved>()
-                .await?;
+            let page = self.saved_page(after.as_deref()).await?;
 
             // total number of items processed by the method
             // note that not all of these items are media, so the downloaded media will be
             // lesser than or equal to the number of items present
-            processed += response.borrow().data.dist;
+            let processed = processed + page.data.dist;
             info!("Number of items processed : {}", processed);
 
-            // if there is a response, continue collecting them into a vector
-            if response.borrow().data.after.as_ref().is_none() {
-                info!("Data gathering complete. Yay.");
-                saved.push(response);
-                complete = true;
-            } else {
-                debug!(
-                    "Processing till: {}",
-                    response.borrow().data.after.as_ref().unwrap()
-                );
-                after = response.borrow().data.after.clone();
-                saved.push(response);
-            }
-        }
-
-        Ok(saved)
+            let next = match page.data.after.clone() {
+                Some(a) => {
+                    debug!("Processing till: {}", a);
+                    Some(Some(a))
+                }
+                None => {
+                    info!("Data gathering complete. Yay.");
+                    None
+                }
+            };
+            let posts = stream::iter(page.data.children.into_iter().map(Ok));
+
+            Ok(Some((posts, (processed, next))))
+        })
+        .try_flatten()
+    }
+
+    /// Fetch the page of saved items starting after the item named `after`
+    async fn saved_page(&self, after: Option<&str>) -> Result<UserSaved, ReddSaverError> {
+        // during the first call to the API, we would not provide the after query parameter
+        // in subsequent calls, we use the value for after from the response of the
+        //  previous request and continue doing so till the value of after is null
+        let endpoint = self
+            .http()
+            .endpoints()
+            .oauth(&format!("user/{}/saved", self.name));
+        let url = match after {
+            Some(a) => format!("{}?after={}", endpoint, a),
+            None => endpoint,
+        };
+
+        let response = self
+            .session
+            .execute(|| {
+                self.http()
+                    .get(&url)
+                    // the maximum number of items returned by the API in a single request is 100
+                    .query(&[("limit", 100)])
+            })
+            .await
+            .and_then(error_for_status)?
+            .json::<UserSaved>()
+            .await?;
+        debug!("Saved Posts: {:#?}", response);
+
+        Ok(response)
+    }
+
+    /// Fetch the posts with the given full names, e.g. `t3_abc123`. Posts that have been
+    /// deleted are missing from the result
+    pub async fn posts(&self, names: &[String]) -> Result<Vec<Post>, ReddSaverError> {
+        let url = self
+            .http()
+            .endpoints()
+            .oauth(&format!("by_id/{}", names.join(",")));
+
+        let response = self
+            .session
+            .execute(|| self.http().get(&url))
+            .await
+            .and_then(error_for_status)?
+            .json::<UserSaved>()
+            .await?;
+        debug!("Posts by ID: {:#?}", response);
+
+        Ok(response.data.children)
     }
 
     pub async fn unsave(&self, name: &str) -> Result<(), ReddSaverError> {
-        let url = format!("https://oauth.reddit.com/api/unsave");
-        let client = reqwest::Client::new();
+        let url = self.http().endpoints().oauth("api/unsave");
         let mut map = HashMap::new();
         map.insert("id", name);
 
         // convenience method to unsave reddit posts
-        let response = client
-            .post(&url)
-            .bearer_auth(&self.auth.access_token)
-            .header(USER_AGENT, get_user_agent_string(None, None))
-            .form(&map)
-            .send()
-            .await?;
+        let response = self
+            .session
+            .execute(|| self.http().post(&url).form(&map))
+            .await
+            .and_then(error_for_status)?;
 
         debug!("Unsave response: {:#?}", response);
 
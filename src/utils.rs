@@ -1,22 +1,25 @@
+use crate::config::Setting;
+use crate::secrets::Secret;
 use rand::Rng;
-use random_names::RandomName;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Generate user agent string of the form <name>:<version>.
-/// If no arguments passed generate random name and number
-pub fn get_user_agent_string(name: Option<String>, version: Option<String>) -> String {
-    if let (Some(v), Some(n)) = (version, name) {
-        format!("{}:{}", n, v)
-    } else {
-        let random_name = RandomName::new()
-            .to_string()
-            .replace(" ", "")
-            .to_lowercase();
-
-        let mut rng = rand::thread_rng();
-        let random_version = rng.gen::<u32>();
-        format!("{}:{}", random_name, random_version)
-    }
+/// Generate user agent string of the form <platform>:<app ID>:<version> (by /u/<username>)
+/// as required by the Reddit API rules
+pub fn get_user_agent_string(username: &str) -> String {
+    format!(
+        "{}:{}:v{} (by /u/{})",
+        env::consts::OS,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        username
+    )
 }
 
 /// Check if a particular path is present on the filesystem
@@ -24,6 +27,42 @@ pub fn check_path_present(file_path: &str) -> bool {
     Path::new(file_path).exists()
 }
 
+/// Write contents to a file that only the current user is allowed to read and write
+pub fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // the mode only applies when the file is created, so tighten existing files as well
+        if path.exists() {
+            set_permissions(path, Permissions::from_mode(0o600))?;
+        }
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(contents)
+}
+
+/// Generate a random string that can be used as the OAuth state parameter
+pub fn random_state() -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(24)
+        .collect()
+}
+
+/// Current time as seconds since the UNIX epoch
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 /// Function that masks sensitive data such as password and client secrets
 pub fn mask_sensitive(word: &str) -> String {
     let word_length = word.len();
@@ -50,10 +89,37 @@ pub fn mask_sensitive(word: &str) -> String {
 }
 
 /// Return delimited subreddit names or EMPTY if None
-pub fn print_subreddits(subreddits: &Option<Vec<&str>>) -> String {
+pub fn print_subreddits(subreddits: &Option<Vec<String>>) -> String {
     return if let Some(s) = subreddits {
         s.join(",")
     } else {
         String::from("<ALL>")
     };
 }
+
+/// Return the masked secret along with where it was read from, or EMPTY if None
+pub fn print_secret(secret: &Option<Secret>) -> String {
+    match secret {
+        Some(s) => format!("{} (from {})", mask_sensitive(&s.value), s.source),
+        None => mask_sensitive(""),
+    }
+}
+
+/// Return the value of a setting along with the layer it was taken from
+pub fn print_setting<T: Display>(setting: &Setting<T>) -> String {
+    format!("{} (from {})", setting.value, setting.layer)
+}
+
+/// Print per-host download limits as `host=limit` pairs
+pub fn print_host_limits(limits: &HashMap<String, usize>) -> String {
+    if limits.is_empty() {
+        return String::from("<DEFAULT>");
+    }
+
+    let mut pairs: Vec<String> = limits
+        .iter()
+        .map(|(host, limit)| format!("{}={}", host, limit))
+        .collect();
+    pairs.sort();
+    pairs.join(",")
+}
@@ -0,0 +1,340 @@
+use crate::errors::ReddSaverError;
+
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::Url;
+
+static JPEG_MAGIC: &[u8] = &[0xFF, 0xD8, 0xFF];
+static PNG_MAGIC: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+static GIF_MAGIC: &[u8] = b"GIF8";
+static WEBM_MAGIC: &[u8] = &[0x1A, 0x45, 0xDF, 0xA3];
+/// Content types that say nothing about the media, so the body has to tell instead
+static GENERIC_CONTENT_TYPES: [&str; 2] = ["application/octet-stream", "binary/octet-stream"];
+static UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+/// Hosts that answer requests for media that were removed by redirecting to a placeholder
+/// or a not found page instead of responding with an error. Hosts sending a not found page
+/// straight away are caught by its content type
+static PLACEHOLDER_HOSTS: [&str; 3] = ["imgur.com", "giphy.com", "redgifs.com"];
+/// Names of the placeholders and not found pages such hosts redirect to, without their
+/// extension, e.g. Imgur's `removed.png`
+static PLACEHOLDER_NAMES: [&str; 6] = [
+    "removed",
+    "404",
+    "notfound",
+    "not-found",
+    "not_found",
+    "deleted",
+];
+/// Extensions media may be saved with
+pub static MEDIA_EXTENSIONS: [&str; 7] = ["jpg", "jpeg", "png", "gif", "webp", "mp4", "webm"];
+
+/// Kind of media a file holds, which decides the content types and formats it may be saved from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaKind {
+    Image,
+    Video,
+}
+
+impl MediaKind {
+    /// Kind of media expected for a file with the given extension, if it is a known one
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "jpg" | "jpeg" | "png" | "gif" | "webp" => Some(MediaKind::Image),
+            "mp4" | "webm" => Some(MediaKind::Video),
+            _ => None,
+        }
+    }
+
+    fn accepts(self, content_type: &str) -> bool {
+        match self {
+            MediaKind::Image => content_type.starts_with("image/"),
+            // the audio track of a video is served on its own as well
+            MediaKind::Video => {
+                content_type.starts_with("video/")
+                    || content_type == "audio/mp4"
+                    || content_type == "audio/webm"
+                    || content_type == "application/mp4"
+            }
+        }
+    }
+}
+
+/// What the first bytes of a response body look like
+#[derive(Debug, PartialEq)]
+enum Sniffed {
+    /// Media in the format usually saved with the given extension
+    Media(&'static str),
+    /// An HTML, XML or JSON document, typically an error page
+    Document,
+    Unknown,
+}
+
+/// Check that a response is the media expected at `url` rather than an error page or a
+/// placeholder, based on where it was redirected to and its content type
+pub fn check_response(
+    url: &str,
+    final_url: &Url,
+    headers: &HeaderMap,
+    expected: Option<MediaKind>,
+) -> Result<(), ReddSaverError> {
+    if is_placeholder(url, final_url) {
+        return Err(ReddSaverError::MediaGone(
+            String::from(url),
+            format!("redirected to placeholder {}", final_url),
+        ));
+    }
+
+    if let Some(content_type) = content_type(headers) {
+        let accepted = match expected {
+            Some(kind) => kind.accepts(&content_type),
+            None => {
+                MediaKind::Image.accepts(&content_type) || MediaKind::Video.accepts(&content_type)
+            }
+        };
+        if !accepted && !GENERIC_CONTENT_TYPES.contains(&content_type.as_str()) {
+            return Err(ReddSaverError::MediaGone(
+                String::from(url),
+                format!("served as {}", content_type),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the beginning of a response body looks like the media expected at `url`.
+/// Bodies in a format that is not recognized are given the benefit of the doubt
+pub fn check_body(
+    url: &str,
+    start: &[u8],
+    expected: Option<MediaKind>,
+) -> Result<(), ReddSaverError> {
+    let reason = match (sniff(start), expected) {
+        (Sniffed::Document, _) => "the response is a document",
+        (Sniffed::Media(extension), Some(expected))
+            if MediaKind::from_extension(extension) != Some(expected) =>
+        {
+            "the response is a different kind of media"
+        }
+        _ => return Ok(()),
+    };
+
+    Err(ReddSaverError::MediaGone(
+        String::from(url),
+        String::from(reason),
+    ))
+}
+
+/// Extension to save the media with, going by the first bytes of its body and otherwise by
+/// its content type, or none if neither is a known media format
+pub fn detect_extension(headers: &HeaderMap, start: &[u8]) -> Option<&'static str> {
+    if let Sniffed::Media(extension) = sniff(start) {
+        return Some(extension);
+    }
+
+    mime_extension(&content_type(headers)?)
+}
+
+/// Extension media of the given MIME type are saved with, if it is a known media format
+pub fn mime_extension(mime_type: &str) -> Option<&'static str> {
+    match mime_type {
+        "image/jpeg" | "image/jpg" | "image/pjpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "video/mp4" | "application/mp4" => Some("mp4"),
+        "video/webm" => Some("webm"),
+        _ => None,
+    }
+}
+
+/// Extension of the media at `url` as given by its path, if it is a known media extension.
+/// This is only a hint, as the host may serve a different format or no extension at all
+pub fn url_extension(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    // Reddit previews name the format they are converted to, e.g. `abc.gif?format=mp4`
+    if let Some((_, format)) = url.query_pairs().find(|(name, _)| name == "format") {
+        if MediaKind::from_extension(&format).is_some() {
+            return Some(format.into_owned());
+        }
+    }
+    let name = url.path_segments()?.next_back()?;
+    let extension = &name[name.rfind('.')? + 1..];
+    MediaKind::from_extension(extension).map(|_| String::from(extension))
+}
+
+/// File name without its media extension, if it has one
+pub fn media_stem(file_name: &str) -> &str {
+    match file_name.rfind('.') {
+        Some(i) if is_media_extension(&file_name[i + 1..]) => &file_name[..i],
+        _ => file_name,
+    }
+}
+
+fn is_media_extension(extension: &str) -> bool {
+    MEDIA_EXTENSIONS
+        .iter()
+        .any(|e| e.eq_ignore_ascii_case(extension))
+}
+
+/// Media type of the response without any parameters, e.g. `image/png`
+fn content_type(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_lowercase())
+}
+
+fn sniff(start: &[u8]) -> Sniffed {
+    if start.starts_with(JPEG_MAGIC) {
+        return Sniffed::Media("jpg");
+    }
+    if start.starts_with(PNG_MAGIC) {
+        return Sniffed::Media("png");
+    }
+    if start.starts_with(GIF_MAGIC) {
+        return Sniffed::Media("gif");
+    }
+    if start.len() >= 12 && &start[..4] == b"RIFF" && &start[8..12] == b"WEBP" {
+        return Sniffed::Media("webp");
+    }
+    if start.len() >= 8 && &start[4..8] == b"ftyp" {
+        return Sniffed::Media("mp4");
+    }
+    if start.starts_with(WEBM_MAGIC) {
+        return Sniffed::Media("webm");
+    }
+
+    // skip a byte order mark and leading whitespace to get to the start of a document
+    let text = if start.starts_with(UTF8_BOM) {
+        &start[UTF8_BOM.len()..]
+    } else {
+        start
+    };
+    match text.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'<') | Some(b'{') | Some(b'[') => Sniffed::Document,
+        _ => Sniffed::Unknown,
+    }
+}
+
+/// Whether the request for `url` ended up at a placeholder for removed media, or was sent
+/// away to the front page of the host
+fn is_placeholder(url: &str, final_url: &Url) -> bool {
+    let host = final_url.host_str().unwrap_or_default().to_lowercase();
+    let known_host = PLACEHOLDER_HOSTS
+        .iter()
+        .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)));
+    if !known_host {
+        return false;
+    }
+
+    let name = final_url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .unwrap_or_default()
+        .to_lowercase();
+    let stem = name.split('.').next().unwrap_or_default();
+    let redirected = Url::parse(url).map_or(true, |u| u != *final_url);
+
+    PLACEHOLDER_NAMES.contains(&stem) || (redirected && name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn content_type(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn bodies_are_recognized_by_their_first_bytes() {
+        assert_eq!(
+            sniff(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00]),
+            Sniffed::Media("jpg")
+        );
+        assert_eq!(sniff(b"GIF89a..."), Sniffed::Media("gif"));
+        assert_eq!(sniff(b"\x00\x00\x00\x20ftypisom"), Sniffed::Media("mp4"));
+        assert_eq!(sniff(b"\n  <!DOCTYPE html><html>"), Sniffed::Document);
+        assert_eq!(sniff(b"{\"error\": 404}"), Sniffed::Document);
+        assert_eq!(sniff(b"image abc"), Sniffed::Unknown);
+
+        let url = "https://i.redd.it/abc.jpg";
+        assert!(check_body(url, b"<html>", Some(MediaKind::Image)).is_err());
+        assert!(check_body(url, b"\x00\x00\x00\x20ftypmp42", Some(MediaKind::Image)).is_err());
+        assert!(check_body(url, b"GIF89a", Some(MediaKind::Image)).is_ok());
+    }
+
+    #[test]
+    fn error_pages_and_placeholders_are_rejected() {
+        let url = "https://i.imgur.com/abc.jpg";
+        let original = Url::parse(url).unwrap();
+        let image = Some(MediaKind::Image);
+
+        assert!(check_response(url, &original, &content_type("image/jpeg"), image).is_ok());
+        assert!(check_response(
+            url,
+            &original,
+            &content_type("application/octet-stream"),
+            image
+        )
+        .is_ok());
+        assert!(check_response(
+            url,
+            &original,
+            &content_type("text/html; charset=utf-8"),
+            image
+        )
+        .is_err());
+        assert!(check_response(url, &original, &content_type("video/mp4"), image).is_err());
+
+        let removed = Url::parse("https://i.imgur.com/removed.png").unwrap();
+        assert!(check_response(url, &removed, &content_type("image/png"), image).is_err());
+
+        let giphy = "https://media.giphy.com/media/abc/giphy.gif";
+        let giphy_original = Url::parse(giphy).unwrap();
+        let not_found = Url::parse("https://giphy.com/404").unwrap();
+        assert!(check_response(giphy, &giphy_original, &content_type("image/gif"), image).is_ok());
+        assert!(check_response(giphy, &not_found, &content_type("image/gif"), image).is_err());
+
+        let redgifs = "https://thumbs2.redgifs.com/HappyDog.mp4";
+        let video = Some(MediaKind::Video);
+        let front_page = Url::parse("https://www.redgifs.com/").unwrap();
+        assert!(check_response(redgifs, &front_page, &content_type("video/mp4"), video).is_err());
+
+        // without an extension to go by, anything but media is rejected
+        assert!(check_response(url, &original, &content_type("image/gif"), None).is_ok());
+        assert!(check_response(url, &original, &content_type("text/html"), None).is_err());
+    }
+
+    #[test]
+    fn extensions_are_decided_by_the_response() {
+        assert_eq!(
+            detect_extension(&content_type("image/jpeg"), b"\x89PNG\r\n\x1a\n..."),
+            Some("png")
+        );
+        assert_eq!(
+            detect_extension(&content_type("image/gif; charset=binary"), b"..."),
+            Some("gif")
+        );
+        assert_eq!(
+            detect_extension(&content_type("application/octet-stream"), b"..."),
+            None
+        );
+
+        assert_eq!(
+            url_extension("https://i.redd.it/abc.PNG?width=640").as_deref(),
+            Some("PNG")
+        );
+        assert_eq!(
+            url_extension("https://preview.redd.it/abc.gif?format=mp4&s=1").as_deref(),
+            Some("mp4")
+        );
+        assert_eq!(url_extension("https://giphy.com/gifs/abc.def/html5"), None);
+        assert_eq!(media_stem("data/pics/a.b_t3_x.jpeg"), "data/pics/a.b_t3_x");
+        assert_eq!(media_stem("data/pics/a.b_t3_x"), "data/pics/a.b_t3_x");
+    }
+}
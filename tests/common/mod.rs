@@ -0,0 +1,471 @@
+//! Stand-in for the Reddit API and the media hosts, along with helpers to run the reddsaver
+//! binary against it in a throwaway home directory
+
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+pub static USERNAME: &str = "tester";
+pub static PASSWORD: &str = "hunter2";
+pub static ACCESS_TOKEN: &str = "mock-access-token";
+
+/// A request received by the mock server
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path including the query string
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Value of a query string or form encoded body parameter
+    pub fn param(&self, name: &str) -> Option<String> {
+        let query = self.target.splitn(2, '?').nth(1).unwrap_or_default();
+        url::form_urlencoded::parse(query.as_bytes())
+            .chain(url::form_urlencoded::parse(self.body.as_bytes()))
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    }
+
+    pub fn is(&self, method: &str, path: &str) -> bool {
+        self.method == method && self.path() == path
+    }
+}
+
+/// A response sent by the mock server
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Number of bytes of the body sent before the connection drops, if it does
+    pub cut_off: Option<usize>,
+}
+
+impl Response {
+    pub fn json(status: u16, body: Value) -> Self {
+        Self::new(status, body.to_string().into_bytes())
+            .header("Content-Type", "application/json; charset=UTF-8")
+    }
+
+    pub fn bytes(body: &[u8]) -> Self {
+        Self::new(200, body.to_vec()).header("Content-Type", "application/octet-stream")
+    }
+
+    pub fn status(status: u16) -> Self {
+        Self::new(status, Vec::new())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Drop the connection after sending the first `sent` bytes of the body
+    pub fn cut_off(mut self, sent: usize) -> Self {
+        self.cut_off = Some(sent);
+        self
+    }
+
+    pub fn new(status: u16, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body,
+            cut_off: None,
+        }
+    }
+}
+
+type Handler = dyn Fn(&Request, &str) -> Response + Send + Sync;
+
+/// HTTP server answering every request with the response returned by the handler, which
+/// is given the request and the base URL of the server
+pub struct MockServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    pub fn start<H>(handler: H) -> Self
+    where
+        H: Fn(&Request, &str) -> Response + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let base = url.clone();
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => continue,
+                };
+                let (base, log, handler) = (base.clone(), log.clone(), handler.clone());
+                // media are downloaded concurrently, so answer every connection on its own thread
+                thread::spawn(move || serve(stream, &base, &log, handler.as_ref()));
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// All requests received so far, in the order they arrived
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Requests received so far for the given method and path
+    pub fn requests_to(&self, method: &str, path: &str) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.is(method, path))
+            .collect()
+    }
+}
+
+fn serve(stream: TcpStream, base: &str, log: &Mutex<Vec<Request>>, handler: &Handler) {
+    let mut reader = BufReader::new(stream);
+    // connections are closed after every response, so there is exactly one request to read
+    let request = match read_request(&mut reader) {
+        Some(r) => r,
+        None => return,
+    };
+    log.lock().unwrap().push(request.clone());
+
+    let response = handler(&request, base);
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    let mut stream = reader.into_inner();
+    let _ = stream.write_all(head.as_bytes());
+    let sent = response.cut_off.unwrap_or(response.body.len());
+    let _ = stream.write_all(&response.body[..sent]);
+    let _ = stream.flush();
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = String::from(parts.next()?);
+    let target = String::from(parts.next()?);
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        let name = header.next()?.trim();
+        let value = header.next().unwrap_or_default().trim();
+        headers.push((String::from(name), String::from(value)));
+    }
+
+    let length = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(Request {
+        method,
+        target,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// Successful response of the access token endpoint
+pub fn token(access_token: &str) -> Response {
+    Response::json(
+        200,
+        json!({
+            "access_token": access_token,
+            "token_type": "bearer",
+            "expires_in": 3600,
+            "scope": "*",
+        }),
+    )
+}
+
+pub fn about() -> Response {
+    Response::json(
+        200,
+        json!({
+            "kind": "t2",
+            "data": {
+                "comment_karma": 1,
+                "created": 1600000000.0,
+                "created_utc": 1600000000.0,
+                "has_subscribed": true,
+                "has_verified_email": true,
+                "hide_from_robots": false,
+                "id": "abc123",
+                "is_employee": false,
+                "is_friend": false,
+                "is_gold": false,
+                "is_mod": false,
+                "link_karma": 1,
+                "name": USERNAME,
+            }
+        }),
+    )
+}
+
+/// Page of the saved listing holding `posts`, followed by the page starting after `after`
+pub fn listing(posts: Vec<Value>, after: Option<&str>) -> Response {
+    Response::json(
+        200,
+        json!({
+            "kind": "Listing",
+            "data": {
+                "modhash": null,
+                "before": null,
+                "after": after,
+                "dist": posts.len(),
+                "children": posts,
+            }
+        }),
+    )
+}
+
+/// Saved link post pointing to `url`
+pub fn post(name: &str, subreddit: &str, url: &str) -> Value {
+    json!({
+        "kind": "t3",
+        "data": {
+            "subreddit": subreddit,
+            "id": name.trim_start_matches("t3_"),
+            "score": 1,
+            "thumbnail": null,
+            "subreddit_id": "t5_mock",
+            "saved": true,
+            "permalink": format!("/r/{}/comments/{}/", subreddit, name),
+            "name": name,
+            "created": 1600000000.0,
+            "url": url,
+            "title": format!("Post {}", name),
+            "created_utc": 1600000000.0,
+            "gallery_data": null,
+            "is_video": false,
+            "media": null,
+        }
+    })
+}
+
+/// Saved gallery post made of the images with the given media IDs
+pub fn gallery(name: &str, subreddit: &str, media_ids: &[&str]) -> Value {
+    let mut post = post(
+        name,
+        subreddit,
+        &format!("https://www.reddit.com/gallery/{}", name),
+    );
+    let items: Vec<Value> = media_ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| json!({ "media_id": id, "id": i }))
+        .collect();
+    post["data"]["gallery_data"] = json!({ "items": items });
+    post
+}
+
+/// Saved Reddit video post whose streams are served by the mock at `base`
+pub fn video(name: &str, subreddit: &str, base: &str) -> Value {
+    let mut post = post(name, subreddit, &format!("https://v.redd.it/{}", name));
+    post["data"]["is_video"] = json!(true);
+    post["data"]["media"] = json!({
+        "reddit_video": {
+            "fallback_url": format!("{}/video/DASH_96.mp4?source=fallback", base),
+            "dash_url": format!("{}/video/DASHPlaylist.mpd", base),
+            "is_gif": false,
+        }
+    });
+    post
+}
+
+/// Streams of the Reddit video served under `/video/`, a DASH manifest with a 360p and a
+/// 720p video and an audio track
+pub fn video_stream(name: &str) -> Response {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/video")
+        .join(name);
+    match std::fs::read(path) {
+        Ok(body) if name.ends_with(".mpd") => {
+            Response::new(200, body).header("Content-Type", "application/dash+xml")
+        }
+        Ok(body) => Response::new(200, body).header("Content-Type", "video/mp4"),
+        Err(_) => Response::status(404),
+    }
+}
+
+/// The saved posts of the mock Reddit: a gallery with two images and a Gfycat link
+pub fn saved_posts() -> Vec<Value> {
+    vec![
+        gallery("t3_first", "pics", &["abc", "def"]),
+        post("t3_second", "gifs", "https://gfycat.com/happydog"),
+    ]
+}
+
+/// Routes of a mock Reddit with two pages of saved posts, whose media are served by
+/// the mock as well
+pub fn reddit(request: &Request, base: &str) -> Response {
+    let saved = format!("/user/{}/saved", USERNAME);
+    let about_path = format!("/user/{}/about", USERNAME);
+
+    match (request.method.as_str(), request.path()) {
+        ("POST", "/api/v1/access_token") => token(ACCESS_TOKEN),
+        ("GET", p) if p == about_path => about(),
+        ("GET", p) if p == saved => match request.param("after").as_deref() {
+            None => listing(vec![saved_posts().remove(0)], Some("t3_first")),
+            Some("t3_first") => listing(vec![saved_posts().remove(1)], None),
+            Some(_) => Response::status(400),
+        },
+        ("GET", p) if p.starts_with("/by_id/") => {
+            let names: Vec<&str> = p.trim_start_matches("/by_id/").split(',').collect();
+            let posts = saved_posts()
+                .into_iter()
+                .filter(|post| names.contains(&post["data"]["name"].as_str().unwrap()))
+                .collect();
+            listing(posts, None)
+        }
+        ("GET", "/gfycat/happydog") => Response::json(
+            200,
+            json!({
+                "gfyItem": {
+                    "gifUrl": format!("{}/files/HappyDog.gif", base),
+                    "mp4Url": format!("{}/files/HappyDog.mp4", base),
+                }
+            }),
+        ),
+        ("GET", "/media/abc.jpg") => Response::bytes(b"image abc"),
+        ("GET", "/media/def.jpg") => Response::bytes(b"image def"),
+        ("GET", "/files/HappyDog.mp4") => Response::bytes(b"video happydog"),
+        ("POST", "/api/unsave") => Response::json(200, json!({})),
+        _ => Response::status(404),
+    }
+}
+
+/// Throwaway home directory with an empty data directory to run reddsaver in
+pub struct Sandbox {
+    pub home: TempDir,
+}
+
+impl Sandbox {
+    pub fn new() -> Self {
+        let home = tempfile::tempdir().unwrap();
+        std::fs::create_dir(home.path().join("data")).unwrap();
+        Self { home }
+    }
+
+    pub fn data_dir(&self) -> PathBuf {
+        self.home.path().join("data")
+    }
+
+    pub fn config_dir(&self) -> PathBuf {
+        self.home.path().join("config")
+    }
+
+    pub fn cache_dir(&self) -> PathBuf {
+        self.home.path().join("cache")
+    }
+
+    /// Command running reddsaver against the real endpoints, isolated from the environment
+    /// and the configuration of the user running the tests
+    pub fn reddsaver_offline(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_reddsaver"));
+        command
+            .env_clear()
+            .current_dir(self.home.path())
+            .env("HOME", self.home.path())
+            .env("XDG_CONFIG_HOME", self.config_dir())
+            .env("XDG_CACHE_HOME", self.cache_dir())
+            .env("RS_LOG", "debug")
+            .env("CLIENT_ID", "mock-client")
+            .env("CLIENT_SECRET", "mock-secret")
+            .env("USERNAME", USERNAME)
+            .env("PASSWORD", PASSWORD)
+            .arg("--data-dir")
+            .arg(self.data_dir());
+        command
+    }
+
+    /// Command running reddsaver against `server`
+    pub fn reddsaver(&self, server: &MockServer) -> Command {
+        let mut command = self.reddsaver_offline();
+        command
+            .env("RS_REDDIT_URL", server.url())
+            .env("RS_OAUTH_URL", server.url())
+            .env("RS_REDDIT_MEDIA_URL", format!("{}/media", server.url()))
+            .env("RS_GFYCAT_API_URL", format!("{}/gfycat", server.url()))
+            .env("RS_REDGIFS_API_URL", format!("{}/redgifs", server.url()))
+            .env("RS_GIPHY_MEDIA_URL", format!("{}/giphy", server.url()));
+        command
+    }
+}
+
+/// Run the command and fail the test with its logs if it did not succeed
+pub fn run(command: &mut Command) -> Output {
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "reddsaver failed with {}\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Contents of all files below `dir`, sorted
+pub fn files(dir: &Path) -> Vec<Vec<u8>> {
+    let mut contents = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            contents.extend(files(&path));
+        } else {
+            contents.push(std::fs::read(&path).unwrap());
+        }
+    }
+    contents.sort();
+    contents
+}
@@ -0,0 +1,694 @@
+//! End to end tests running the reddsaver binary against a local stand-in for Reddit
+
+mod common;
+
+use common::*;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn bearer() -> String {
+    format!("Bearer {}", ACCESS_TOKEN)
+}
+
+#[test]
+fn downloads_saved_media_from_every_page_and_unsaves_posts() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--unsave"));
+
+    let login = server.requests_to("POST", "/api/v1/access_token");
+    assert_eq!(login.len(), 1);
+    assert_eq!(login[0].param("grant_type").as_deref(), Some("password"));
+    assert_eq!(login[0].param("username").as_deref(), Some(USERNAME));
+
+    let pages = server.requests_to("GET", "/user/tester/saved");
+    assert_eq!(pages.len(), 2);
+    assert_eq!(pages[0].param("after"), None);
+    assert_eq!(pages[1].param("after").as_deref(), Some("t3_first"));
+    for page in &pages {
+        assert_eq!(page.header("authorization"), Some(bearer().as_str()));
+        assert!(page.header("user-agent").unwrap().contains("/u/tester"));
+    }
+
+    assert_eq!(
+        files(&sandbox.data_dir()),
+        vec![
+            b"image abc".to_vec(),
+            b"image def".to_vec(),
+            b"video happydog".to_vec()
+        ]
+    );
+    assert!(sandbox.data_dir().join("pics").is_dir());
+    assert!(sandbox.data_dir().join("gifs").is_dir());
+
+    let mut unsaved: Vec<String> = server
+        .requests_to("POST", "/api/unsave")
+        .iter()
+        .map(|r| r.param("id").unwrap())
+        .collect();
+    unsaved.sort();
+    assert_eq!(unsaved, vec!["t3_first", "t3_second"]);
+}
+
+#[test]
+fn dry_run_lists_media_without_downloading() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--dry-run"));
+
+    assert!(files(&sandbox.data_dir()).is_empty());
+    assert!(server.requests_to("GET", "/media/abc.jpg").is_empty());
+    assert!(server.requests_to("POST", "/api/unsave").is_empty());
+}
+
+#[test]
+fn media_already_downloaded_are_skipped() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+
+    run(&mut sandbox.reddsaver(&server));
+    run(&mut sandbox.reddsaver(&server));
+
+    assert_eq!(server.requests_to("GET", "/media/abc.jpg").len(), 1);
+    assert_eq!(files(&sandbox.data_dir()).len(), 3);
+}
+
+#[test]
+fn stored_refresh_token_is_used_instead_of_the_password() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+    let credentials = sandbox.config_dir().join("reddsaver");
+    fs::create_dir_all(&credentials).unwrap();
+    fs::write(
+        credentials.join("tester.refresh_token"),
+        "mock-refresh-token",
+    )
+    .unwrap();
+
+    run(sandbox.reddsaver(&server).env_remove("PASSWORD"));
+
+    let login = server.requests_to("POST", "/api/v1/access_token");
+    assert_eq!(login.len(), 1);
+    assert_eq!(
+        login[0].param("grant_type").as_deref(),
+        Some("refresh_token")
+    );
+    assert_eq!(
+        login[0].param("refresh_token").as_deref(),
+        Some("mock-refresh-token")
+    );
+    assert_eq!(login[0].param("password"), None);
+}
+
+#[test]
+fn cached_access_token_is_reused_by_the_next_run() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--dry-run"));
+    run(sandbox.reddsaver(&server).arg("--dry-run"));
+
+    assert_eq!(server.requests_to("POST", "/api/v1/access_token").len(), 1);
+    assert_eq!(server.requests_to("GET", "/user/tester/about").len(), 2);
+}
+
+#[test]
+fn rejected_access_token_is_renewed() {
+    let logins = AtomicUsize::new(0);
+    let server = MockServer::start(move |request, base| {
+        if request.is("POST", "/api/v1/access_token") {
+            // the first token handed out is revoked straight away
+            return match logins.fetch_add(1, Ordering::SeqCst) {
+                0 => token("revoked-token"),
+                _ => token(ACCESS_TOKEN),
+            };
+        }
+        if request.header("authorization") == Some("Bearer revoked-token") {
+            return Response::status(401);
+        }
+        reddit(request, base)
+    });
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--dry-run"));
+
+    assert_eq!(server.requests_to("POST", "/api/v1/access_token").len(), 2);
+    let about = server.requests_to("GET", "/user/tester/about");
+    assert_eq!(about.len(), 2);
+    assert_eq!(about[1].header("authorization"), Some(bearer().as_str()));
+}
+
+#[test]
+fn rate_limited_requests_are_retried_after_backing_off() {
+    let limited = AtomicUsize::new(0);
+    let server = MockServer::start(move |request, base| {
+        if request.is("GET", "/user/tester/saved") && limited.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Response::status(429).header("Retry-After", "1");
+        }
+        reddit(request, base).header("X-Ratelimit-Remaining", "100")
+    });
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--dry-run"));
+
+    let pages = server.requests_to("GET", "/user/tester/saved");
+    assert_eq!(pages.len(), 3);
+    assert_eq!(pages[0].param("after"), pages[1].param("after"));
+}
+
+#[test]
+fn failed_login_is_reported() {
+    let server = MockServer::start(|request, base| {
+        if request.is("POST", "/api/v1/access_token") {
+            return Response::json(200, serde_json::json!({ "error": "invalid_grant" }));
+        }
+        reddit(request, base)
+    });
+    let sandbox = Sandbox::new();
+
+    let output = sandbox.reddsaver(&server).output().unwrap();
+
+    assert!(!output.status.success());
+    assert!(server.requests_to("GET", "/user/tester/saved").is_empty());
+}
+
+#[test]
+fn wrong_password_is_not_tried_with_other_one_time_passwords() {
+    let server = MockServer::start(|request, base| {
+        if request.is("POST", "/api/v1/access_token") {
+            return Response::json(200, serde_json::json!({ "error": "invalid_grant" }));
+        }
+        reddit(request, base)
+    });
+    let sandbox = Sandbox::new();
+
+    let output = sandbox
+        .reddsaver(&server)
+        .env("TOTP_SECRET", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert_eq!(server.requests_to("POST", "/api/v1/access_token").len(), 1);
+}
+
+#[test]
+fn downloads_start_before_the_last_page_is_fetched() {
+    let media_requested = Arc::new(AtomicBool::new(false));
+    let overlapped = Arc::new(AtomicBool::new(false));
+    let (requested, seen) = (media_requested.clone(), overlapped.clone());
+    let server = MockServer::start(move |request, base| {
+        if request.path() == "/media/abc.jpg" {
+            requested.store(true, Ordering::SeqCst);
+        }
+        if request.param("after").is_some() {
+            // hold back the last page until the media of the first one are being downloaded
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !requested.load(Ordering::SeqCst) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            seen.store(requested.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+        reddit(request, base)
+    });
+    let sandbox = Sandbox::new();
+
+    run(&mut sandbox.reddsaver(&server));
+
+    assert!(overlapped.load(Ordering::SeqCst));
+    assert_eq!(files(&sandbox.data_dir()).len(), 3);
+}
+
+/// Mock Reddit whose saved listing, one post per page, leaves out the posts unsaved so far
+/// like Reddit does. Pages after a post that is not saved anymore are empty
+fn shrinking_listing() -> MockServer {
+    let unsaved = Arc::new(Mutex::new(Vec::new()));
+    MockServer::start(move |request, base| {
+        let saved = format!("/user/{}/saved", USERNAME);
+        match request.path() {
+            "/api/unsave" => {
+                unsaved.lock().unwrap().push(request.param("id").unwrap());
+                reddit(request, base)
+            }
+            p if p == saved => {
+                let after = request.param("after");
+                if after.is_some() {
+                    // give the posts of the earlier pages a moment to be unsaved
+                    let deadline = Instant::now() + Duration::from_secs(1);
+                    while unsaved.lock().unwrap().is_empty() && Instant::now() < deadline {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                }
+                let unsaved = unsaved.lock().unwrap();
+                let posts: Vec<serde_json::Value> = vec![
+                    gallery("t3_first", "pics", &["abc"]),
+                    gallery("t3_other", "pics", &["def"]),
+                    post("t3_second", "gifs", "https://gfycat.com/happydog"),
+                ]
+                .into_iter()
+                .filter(|p| !unsaved.iter().any(|name| p["data"]["name"] == *name))
+                .collect();
+                let name =
+                    |p: &serde_json::Value| String::from(p["data"]["name"].as_str().unwrap());
+                let start = match after {
+                    None => 0,
+                    Some(after) => match posts.iter().position(|p| name(p) == after) {
+                        Some(i) => i + 1,
+                        None => return listing(Vec::new(), None),
+                    },
+                };
+                let next = posts.get(start + 1).map(|_| name(&posts[start]));
+                listing(
+                    posts.into_iter().skip(start).take(1).collect(),
+                    next.as_deref(),
+                )
+            }
+            _ => reddit(request, base),
+        }
+    })
+}
+
+#[test]
+fn posts_are_unsaved_once_the_listing_has_been_paged_through() {
+    let server = shrinking_listing();
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--unsave"));
+
+    assert_eq!(server.requests_to("GET", "/user/tester/saved").len(), 3);
+    assert_eq!(files(&sandbox.data_dir()).len(), 3);
+    let mut unsaved: Vec<String> = server
+        .requests_to("POST", "/api/unsave")
+        .iter()
+        .map(|r| r.param("id").unwrap())
+        .collect();
+    unsaved.sort();
+    assert_eq!(unsaved, vec!["t3_first", "t3_other", "t3_second"]);
+}
+
+#[test]
+fn downloads_from_a_host_are_limited() {
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let (current, peak) = (in_flight.clone(), most.clone());
+    let server = MockServer::start(move |request, base| {
+        let media = request.path().starts_with("/media/") || request.path().starts_with("/files/");
+        if media {
+            let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            current.fetch_sub(1, Ordering::SeqCst);
+        }
+        reddit(request, base)
+    });
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--max-per-host").arg("1"));
+
+    assert_eq!(files(&sandbox.data_dir()).len(), 3);
+    assert_eq!(most.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn partial_downloads_of_an_interrupted_run_are_removed() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+    run(&mut sandbox.reddsaver(&server));
+    let pics = sandbox.data_dir().join("pics");
+    let saved = fs::read_dir(&pics).unwrap().next().unwrap().unwrap().path();
+    let stale = std::path::PathBuf::from(format!("{}.part", saved.display()));
+    fs::write(&stale, "half an image").unwrap();
+    // partial downloads of media this run does not come across may be another account's
+    let unknown = pics.join("interrupted.jpg.part");
+    fs::write(&unknown, "half an image").unwrap();
+    // partial downloads written just before the run started are not considered stale yet
+    thread::sleep(Duration::from_millis(1100));
+
+    run(&mut sandbox.reddsaver(&server));
+
+    assert!(!stale.exists());
+    assert!(unknown.exists());
+}
+
+/// Mock Reddit honouring range requests for the video, whose download drops halfway the first
+/// time. If `changed`, a different video is served from then on
+fn flaky_video(changed: bool) -> MockServer {
+    let attempts = AtomicUsize::new(0);
+    MockServer::start(move |request, base| {
+        if request.path() != "/files/HappyDog.mp4" {
+            return reddit(request, base);
+        }
+        let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+        let (etag, body): (&str, &[u8]) = match attempt {
+            n if n > 0 && changed => ("\"v2\"", b"video happydog, retaken"),
+            _ => ("\"v1\"", b"video happydog"),
+        };
+
+        let range = request
+            .header("range")
+            .and_then(|r| r.strip_prefix("bytes="))
+            .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+        let mut response = match range {
+            Some(start) if request.header("if-range") == Some(etag) => {
+                let mut partial = Response::bytes(&body[start..]).header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", start, body.len() - 1, body.len()),
+                );
+                partial.status = 206;
+                partial
+            }
+            _ => Response::bytes(body),
+        };
+        response = response
+            .header("Accept-Ranges", "bytes")
+            .header("ETag", etag);
+        if attempt == 0 {
+            response = response.cut_off(6);
+        }
+        response
+    })
+}
+
+fn partial_files(dir: &std::path::Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".part") || name.ends_with(".resume"))
+        .collect()
+}
+
+#[test]
+fn interrupted_downloads_are_resumed() {
+    let server = flaky_video(false);
+    let sandbox = Sandbox::new();
+    let gifs = sandbox.data_dir().join("gifs");
+
+    // give up on the download straight away, as if the run was interrupted
+    run(sandbox.reddsaver(&server).arg("--retry-attempts").arg("1"));
+    // only the partial download and what is needed to resume it are there
+    assert_eq!(partial_files(&gifs).len(), 2);
+    assert_eq!(fs::read_dir(&gifs).unwrap().count(), 2);
+
+    run(&mut sandbox.reddsaver(&server));
+
+    let video = server.requests_to("GET", "/files/HappyDog.mp4");
+    assert_eq!(video.len(), 2);
+    assert_eq!(video[1].header("range"), Some("bytes=6-"));
+    assert_eq!(video[1].header("if-range"), Some("\"v1\""));
+    assert_eq!(files(&gifs), vec![b"video happydog".to_vec()]);
+    assert!(partial_files(&gifs).is_empty());
+}
+
+#[test]
+fn changed_media_are_downloaded_again_instead_of_resumed() {
+    let server = flaky_video(true);
+    let sandbox = Sandbox::new();
+    let gifs = sandbox.data_dir().join("gifs");
+
+    run(sandbox.reddsaver(&server).arg("--retry-attempts").arg("1"));
+    run(&mut sandbox.reddsaver(&server));
+
+    assert_eq!(files(&gifs), vec![b"video happydog, retaken".to_vec()]);
+    assert!(partial_files(&gifs).is_empty());
+}
+
+#[test]
+fn transient_download_failures_are_retried() {
+    let failures = AtomicUsize::new(0);
+    let server = MockServer::start(move |request, base| match request.path() {
+        "/media/abc.jpg" if failures.fetch_add(1, Ordering::SeqCst) < 2 => Response::status(503),
+        "/media/def.jpg" => Response::status(404),
+        _ => reddit(request, base),
+    });
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--retry-delay").arg("10"));
+
+    assert_eq!(server.requests_to("GET", "/media/abc.jpg").len(), 3);
+    assert_eq!(server.requests_to("GET", "/media/def.jpg").len(), 1);
+    assert_eq!(
+        files(&sandbox.data_dir().join("pics")),
+        vec![b"image abc".to_vec()]
+    );
+}
+
+#[test]
+fn interrupted_downloads_are_resumed_when_retried() {
+    let server = flaky_video(false);
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--retry-delay").arg("10"));
+
+    let video = server.requests_to("GET", "/files/HappyDog.mp4");
+    assert_eq!(video.len(), 2);
+    assert_eq!(video[1].header("range"), Some("bytes=6-"));
+    assert_eq!(
+        files(&sandbox.data_dir().join("gifs")),
+        vec![b"video happydog".to_vec()]
+    );
+}
+
+#[test]
+fn failures_are_reported_without_affecting_other_posts() {
+    let server = MockServer::start(|request, base| match request.path() {
+        "/media/def.jpg" => Response::status(503),
+        "/gfycat/happydog" => Response::status(500),
+        _ => reddit(request, base),
+    });
+    let sandbox = Sandbox::new();
+
+    run(sandbox
+        .reddsaver(&server)
+        .arg("--unsave")
+        .arg("--retry-attempts")
+        .arg("1"));
+
+    assert_eq!(
+        files(&sandbox.data_dir().join("pics")),
+        vec![b"image abc".to_vec()]
+    );
+    // posts are kept saved until all their media are downloaded
+    assert!(server.requests_to("POST", "/api/unsave").is_empty());
+
+    let report = fs::read(sandbox.data_dir().join("failures-tester.json")).unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&report).unwrap();
+    assert_eq!(report["account"], "tester");
+    let mut failures: Vec<(String, Option<String>)> = report["failures"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| {
+            let post = String::from(f["post"].as_str().unwrap());
+            let url = f["url"].as_str().map(String::from);
+            (post, url)
+        })
+        .collect();
+    failures.sort();
+    assert_eq!(
+        failures,
+        vec![
+            (
+                String::from("t3_first"),
+                Some(format!("{}/media/def.jpg", server.url()))
+            ),
+            (String::from("t3_second"), None),
+        ]
+    );
+}
+
+#[test]
+fn failures_are_reported_when_the_listing_fails() {
+    let server =
+        MockServer::start(
+            |request, base| match (request.path(), request.param("after")) {
+                ("/media/def.jpg", _) => Response::status(503),
+                ("/user/tester/saved", Some(_)) => Response::status(403),
+                _ => reddit(request, base),
+            },
+        );
+    let sandbox = Sandbox::new();
+
+    let output = sandbox
+        .reddsaver(&server)
+        .arg("--retry-attempts")
+        .arg("1")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    // the failure of the first page is kept for `reddsaver retry`
+    let report = fs::read(sandbox.data_dir().join("failures-tester.json")).unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&report).unwrap();
+    let failures = report["failures"].as_array().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(
+        failures[0]["url"],
+        format!("{}/media/def.jpg", server.url()).as_str()
+    );
+}
+
+#[test]
+fn error_pages_are_not_saved_as_media() {
+    let server = MockServer::start(|request, base| match request.path() {
+        "/media/def.jpg" => Response::new(200, b"<!DOCTYPE html><p>Not found</p>".to_vec())
+            .header("Content-Type", "text/html; charset=utf-8"),
+        _ => reddit(request, base),
+    });
+    let sandbox = Sandbox::new();
+
+    let output = run(&mut sandbox.reddsaver(&server));
+
+    assert_eq!(
+        files(&sandbox.data_dir().join("pics")),
+        vec![b"image abc".to_vec()]
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Number of media gone: 1"));
+
+    // gone media are logged on their own rather than reported for another attempt
+    assert!(!sandbox.data_dir().join("failures-tester.json").exists());
+    let log = fs::read(sandbox.data_dir().join("dead-tester.json")).unwrap();
+    let log: serde_json::Value = serde_json::from_slice(&log).unwrap();
+    let failures = log["failures"].as_array().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(
+        failures[0]["url"],
+        format!("{}/media/def.jpg", server.url()).as_str()
+    );
+    assert_eq!(failures[0]["dead"], true);
+}
+
+#[test]
+fn media_are_saved_with_the_extension_of_their_format() {
+    let png = b"\x89PNG\r\n\x1a\nimage def";
+    let server = MockServer::start(move |request, base| match request.path() {
+        "/media/def.jpg" => Response::new(200, png.to_vec()).header("Content-Type", "image/png"),
+        _ => reddit(request, base),
+    });
+    let sandbox = Sandbox::new();
+    let pics = sandbox.data_dir().join("pics");
+
+    run(&mut sandbox.reddsaver(&server));
+    run(&mut sandbox.reddsaver(&server));
+
+    let mut extensions: Vec<String> = fs::read_dir(&pics)
+        .unwrap()
+        .map(|e| {
+            let path = e.unwrap().path();
+            path.extension().unwrap().to_string_lossy().into_owned()
+        })
+        .collect();
+    extensions.sort();
+    assert_eq!(extensions, vec!["jpg", "png"]);
+    // the second run finds the media under its new name and skips it
+    assert_eq!(server.requests_to("GET", "/media/def.jpg").len(), 1);
+}
+
+#[test]
+fn api_errors_are_reported_with_their_status() {
+    let server = MockServer::start(|request, base| match request.path() {
+        "/user/tester/saved" => Response::new(403, b"<html>Forbidden</html>".to_vec()),
+        _ => reddit(request, base),
+    });
+    let sandbox = Sandbox::new();
+
+    let output = sandbox.reddsaver(&server).output().unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("responded with HTTP 403"));
+}
+
+#[test]
+fn failed_unsaves_are_reported() {
+    let server = MockServer::start(|request, base| match request.path() {
+        "/api/unsave" => Response::status(500),
+        _ => reddit(request, base),
+    });
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--unsave"));
+
+    let report = fs::read(sandbox.data_dir().join("failures-tester.json")).unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&report).unwrap();
+    let mut posts: Vec<&str> = report["failures"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["post"].as_str().unwrap())
+        .collect();
+    posts.sort();
+    assert_eq!(posts, vec!["t3_first", "t3_second"]);
+}
+
+/// Configuration file listing an account for each of the given usernames
+fn accounts_config(sandbox: &Sandbox, usernames: &[&str]) -> std::path::PathBuf {
+    let accounts: String = usernames
+        .iter()
+        .map(|u| {
+            format!(
+                "[[accounts]]\nclient_id = \"mock-client\"\nclient_secret = \"mock-secret\"\nusername = \"{}\"\n",
+                u
+            )
+        })
+        .collect();
+    let path = sandbox.data_dir().join("accounts.toml");
+    fs::write(&path, accounts).unwrap();
+    path
+}
+
+#[test]
+fn password_from_stdin_is_used_for_the_configured_account() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+    let config = accounts_config(&sandbox, &[USERNAME]);
+
+    let mut child = sandbox
+        .reddsaver(&server)
+        .arg("--config")
+        .arg(&config)
+        .arg("--password-stdin")
+        .arg("--dry-run")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"piped\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let login = server.requests_to("POST", "/api/v1/access_token");
+    assert_eq!(login[0].param("password").as_deref(), Some("piped"));
+}
+
+#[test]
+fn password_from_stdin_is_refused_with_several_accounts() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+    let config = accounts_config(&sandbox, &[USERNAME, "other"]);
+
+    let output = sandbox
+        .reddsaver(&server)
+        .arg("--config")
+        .arg(&config)
+        .arg("--password-stdin")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("PasswordStdinAmbiguous(2)"));
+    assert!(server
+        .requests_to("POST", "/api/v1/access_token")
+        .is_empty());
+}
@@ -0,0 +1,107 @@
+//! Tests downloading Reddit galleries, whose items are described by the media metadata of
+//! their post
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use std::fs;
+
+/// A gallery with a PNG image with a caption and link, an item with a caption Reddit failed
+/// to process and an animated image served as MP4
+fn saved_gallery(request: &Request, base: &str) -> Response {
+    let saved = format!("/user/{}/saved", USERNAME);
+    match request.path() {
+        p if p == saved => {
+            let mut post = gallery("t3_gallery", "pics", &["png", "broken", "anim"]);
+            let items = &mut post["data"]["gallery_data"]["items"];
+            items[0]["caption"] = json!("A caption");
+            items[0]["outbound_url"] = json!("https://example.com/source");
+            items[1]["caption"] = json!("Never saved");
+            post["data"]["media_metadata"] = json!({
+                "png": {
+                    "status": "valid",
+                    "e": "Image",
+                    "m": "image/png",
+                    "s": { "u": format!("{}/preview/png.png?width=640&amp;s=1", base) },
+                },
+                "broken": { "status": "failed" },
+                "anim": {
+                    "status": "valid",
+                    "e": "AnimatedImage",
+                    "m": "image/gif",
+                    "s": {
+                        "gif": format!("{}/preview/anim.gif?s=1", base),
+                        "mp4": format!("{}/preview/anim.gif?format=mp4&amp;s=1", base),
+                    },
+                },
+            });
+            listing(vec![post], None)
+        }
+        "/media/png.png" => Response::new(200, b"\x89PNG\r\n\x1a\nimage".to_vec())
+            .header("Content-Type", "image/png"),
+        // the ampersands of the URL are unescaped
+        "/preview/anim.gif"
+            if request.param("format").as_deref() == Some("mp4")
+                && request.param("s").as_deref() == Some("1") =>
+        {
+            Response::new(200, b"\x00\x00\x00\x20ftypmp42".to_vec())
+                .header("Content-Type", "video/mp4")
+        }
+        _ => reddit(request, base),
+    }
+}
+
+#[test]
+fn gallery_items_are_saved_in_their_order_and_format() {
+    let server = MockServer::start(saved_gallery);
+    let sandbox = Sandbox::new();
+    let pics = sandbox.data_dir().join("pics");
+
+    let output = run(&mut sandbox.reddsaver(&server));
+
+    let mut names: Vec<String> = fs::read_dir(&pics)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    let suffixes: Vec<&str> = names.iter().map(|n| &n[n.len() - 6..]).collect();
+    assert_eq!(suffixes, vec!["01.png", "01.txt", "03.mp4"]);
+    assert_eq!(
+        fs::read_to_string(pics.join(&names[1])).unwrap(),
+        "A caption\nhttps://example.com/source\n"
+    );
+
+    // the failed item is reported without being requested, and without leaving its caption
+    assert!(server.requests_to("GET", "/media/broken.jpg").is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Number of media gone: 1"));
+    let report = fs::read(sandbox.data_dir().join("dead-tester.json")).unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&report).unwrap();
+    let failures = report["failures"].as_array().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0]["dead"], true);
+}
+
+#[test]
+fn gallery_items_saved_under_their_former_names_are_skipped() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+    let pics = sandbox.data_dir().join("pics");
+    // earlier versions named gallery items after the URL of their JPG image
+    let url = format!("{}/media/abc.jpg", server.url());
+    fs::create_dir_all(&pics).unwrap();
+    fs::write(
+        pics.join(format!("img-{:x}.jpg", md5::compute(url))),
+        b"image abc",
+    )
+    .unwrap();
+
+    run(&mut sandbox.reddsaver(&server));
+
+    assert!(server.requests_to("GET", "/media/abc.jpg").is_empty());
+    assert_eq!(server.requests_to("GET", "/media/def.jpg").len(), 1);
+    assert_eq!(
+        files(&pics),
+        vec![b"image abc".to_vec(), b"image def".to_vec()]
+    );
+}
@@ -0,0 +1,144 @@
+//! Tests downloading Reddit videos, whose video and audio are served as separate streams
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use std::fs;
+
+fn saved_video(request: &Request, base: &str) -> Response {
+    let saved = format!("/user/{}/saved", USERNAME);
+    match request.path() {
+        p if p == saved => listing(vec![video("t3_vid", "videos", base)], None),
+        p if p.starts_with("/video/") => video_stream(p.trim_start_matches("/video/")),
+        _ => reddit(request, base),
+    }
+}
+
+#[test]
+fn video_and_audio_are_merged_into_one_file() {
+    let server = MockServer::start(saved_video);
+    let sandbox = Sandbox::new();
+    let videos = sandbox.data_dir().join("videos");
+
+    run(&mut sandbox.reddsaver(&server));
+
+    // only the merged video is left behind
+    let saved: Vec<_> = fs::read_dir(&videos).unwrap().collect();
+    assert_eq!(saved.len(), 1);
+    let path = saved[0].as_ref().unwrap().path();
+    assert_eq!(path.extension().unwrap(), "mp4");
+    let merged = fs::read(path).unwrap();
+    assert_eq!(&merged[4..8], b"ftyp");
+    let contains = |needle: &[u8]| merged.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"video frames"));
+    assert!(contains(b"audio samples"));
+
+    // the merged video is found and skipped by the next run
+    run(&mut sandbox.reddsaver(&server));
+    assert_eq!(server.requests_to("GET", "/video/DASH_720.mp4").len(), 1);
+}
+
+#[test]
+fn videos_without_audio_are_saved_as_they_are() {
+    let server = MockServer::start(|request, base| match request.path() {
+        "/video/DASH_AUDIO_128.mp4" => Response::status(404),
+        _ => saved_video(request, base),
+    });
+    let sandbox = Sandbox::new();
+
+    run(&mut sandbox.reddsaver(&server));
+
+    let saved = files(&sandbox.data_dir().join("videos"));
+    assert_eq!(saved.len(), 1);
+    assert_eq!(
+        saved[0],
+        fs::read(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/video/DASH_720.mp4")
+        )
+        .unwrap()
+    );
+}
+
+#[test]
+fn videos_are_downloaded_in_the_requested_quality() {
+    let server = MockServer::start(saved_video);
+    let sandbox = Sandbox::new();
+
+    run(sandbox
+        .reddsaver(&server)
+        .arg("--video-quality")
+        .arg("480p"));
+
+    assert_eq!(server.requests_to("GET", "/video/DASH_360.mp4").len(), 1);
+    assert!(server.requests_to("GET", "/video/DASH_720.mp4").is_empty());
+    let merged = &files(&sandbox.data_dir().join("videos"))[0];
+    let contains = |needle: &[u8]| merged.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"small video frames"));
+    assert!(contains(b"audio samples"));
+
+    // the file is named after the fallback URL, so it is found whatever the quality
+    let fallback = format!("{}/video/DASH_96.mp4", server.url());
+    let name = format!("img-{:x}.mp4", md5::compute(fallback));
+    assert!(sandbox.data_dir().join("videos").join(name).exists());
+    run(&mut sandbox.reddsaver(&server));
+    assert!(server.requests_to("GET", "/video/DASH_720.mp4").is_empty());
+    assert_eq!(files(&sandbox.data_dir().join("videos")).len(), 1);
+}
+
+#[test]
+fn the_fallback_video_is_saved_when_the_manifest_fails() {
+    let server = MockServer::start(|request, base| match request.path() {
+        "/video/DASHPlaylist.mpd" => Response::status(500),
+        "/video/DASH_96.mp4" => video_stream("DASH_360.mp4"),
+        _ => saved_video(request, base),
+    });
+    let sandbox = Sandbox::new();
+
+    run(sandbox.reddsaver(&server).arg("--retry-attempts").arg("1"));
+
+    assert_eq!(server.requests_to("GET", "/video/DASH_96.mp4").len(), 1);
+    assert!(server
+        .requests_to("GET", "/video/DASH_AUDIO_128.mp4")
+        .is_empty());
+    let saved = files(&sandbox.data_dir().join("videos"));
+    assert_eq!(saved.len(), 1);
+    assert!(!sandbox.data_dir().join("failures-tester.json").exists());
+}
+
+#[test]
+fn videos_without_a_dash_manifest_are_found_from_their_hls_playlist() {
+    let server = MockServer::start(|request, base| {
+        let saved = format!("/user/{}/saved", USERNAME);
+        match request.path() {
+            p if p == saved => {
+                let mut post = video("t3_vid", "videos", base);
+                let reddit_video = &mut post["data"]["media"]["reddit_video"];
+                reddit_video["dash_url"] = json!(null);
+                reddit_video["hls_url"] = json!(format!("{}/video/HLSPlaylist.m3u8", base));
+                listing(vec![post], None)
+            }
+            _ => saved_video(request, base),
+        }
+    });
+    let sandbox = Sandbox::new();
+
+    run(sandbox
+        .reddsaver(&server)
+        .arg("--video-quality")
+        .arg("480p"));
+
+    // the files of the variant and the audio are downloaded rather than their segments
+    assert_eq!(server.requests_to("GET", "/video/DASH_360.mp4").len(), 1);
+    assert_eq!(
+        server.requests_to("GET", "/video/DASH_AUDIO_128.mp4").len(),
+        1
+    );
+    assert!(server.requests_to("GET", "/video/HLS_360.m3u8").is_empty());
+    let saved = files(&sandbox.data_dir().join("videos"));
+    assert_eq!(saved.len(), 1);
+    let contains = |needle: &[u8]| saved[0].windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"small video frames"));
+    assert!(contains(b"audio samples"));
+}
@@ -0,0 +1,107 @@
+//! Tests recording runs against a local stand-in for Reddit and replaying them offline
+
+mod common;
+
+use common::*;
+use std::fs;
+use std::path::PathBuf;
+
+/// Responses recorded for a saved listing with a post for every supported media host
+fn media_hosts_fixtures() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay/media_hosts")
+}
+
+#[test]
+fn recorded_run_can_be_replayed_without_the_network() {
+    let server = MockServer::start(reddit);
+    let recording = Sandbox::new();
+    let fixtures = recording.home.path().join("fixtures");
+
+    run(recording
+        .reddsaver(&server)
+        .arg("--record")
+        .arg(&fixtures)
+        .arg("--unsave"));
+    let requests = server.requests().len();
+
+    let replaying = Sandbox::new();
+    run(replaying
+        .reddsaver(&server)
+        .arg("--replay")
+        .arg(&fixtures)
+        .arg("--unsave"));
+
+    assert_eq!(server.requests().len(), requests);
+    assert_eq!(files(&replaying.data_dir()), files(&recording.data_dir()));
+    assert_eq!(files(&replaying.data_dir()).len(), 3);
+}
+
+#[test]
+fn secrets_are_not_recorded() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+    let fixtures = sandbox.home.path().join("fixtures");
+
+    run(sandbox.reddsaver(&server).arg("--record").arg(&fixtures));
+
+    for entry in fs::read_dir(&fixtures).unwrap() {
+        let contents = fs::read(entry.unwrap().path()).unwrap();
+        let contents = String::from_utf8_lossy(&contents);
+        assert!(!contents.contains(ACCESS_TOKEN));
+        assert!(!contents.contains(PASSWORD));
+    }
+    // the token cache is bypassed, so that a replay logs in the same way as the recording
+    assert!(!sandbox.cache_dir().join("reddsaver").exists());
+}
+
+#[test]
+fn replay_fails_when_a_response_was_not_recorded() {
+    let sandbox = Sandbox::new();
+    let empty = sandbox.home.path().join("fixtures");
+    fs::create_dir(&empty).unwrap();
+
+    let output = sandbox
+        .reddsaver_offline()
+        .arg("--replay")
+        .arg(&empty)
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("No recorded response for `POST https://www.reddit.com/api/v1/access_token`"));
+}
+
+#[test]
+fn media_of_every_supported_host_are_found() {
+    let sandbox = Sandbox::new();
+
+    run(sandbox
+        .reddsaver_offline()
+        .arg("--replay")
+        .arg(media_hosts_fixtures()));
+
+    let data = sandbox.data_dir();
+    assert_eq!(
+        files(&data.join("pics")),
+        vec![
+            b"gallery image 1".to_vec(),
+            b"gallery image 2".to_vec(),
+            b"i.redd.it image".to_vec(),
+        ]
+    );
+    assert_eq!(
+        files(&data.join("videos")),
+        vec![b"v.redd.it video".to_vec()]
+    );
+    assert_eq!(
+        files(&data.join("gifs")),
+        vec![
+            b"gfycat video".to_vec(),
+            b"giphy gif".to_vec(),
+            b"imgur video".to_vec(),
+            b"redgifs video".to_vec(),
+        ]
+    );
+    assert!(!data.join("AskReddit").exists());
+}
@@ -0,0 +1,159 @@
+//! Tests of `reddsaver retry`, which only makes another attempt at what failed before
+
+mod common;
+
+use common::*;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Mock Reddit where one of the gallery images and the Gfycat API fail while `broken` is set
+fn unreliable(broken: Arc<AtomicBool>) -> MockServer {
+    MockServer::start(move |request, base| {
+        if broken.load(Ordering::SeqCst) {
+            match request.path() {
+                "/media/def.jpg" => return Response::status(503),
+                "/gfycat/happydog" => return Response::status(500),
+                _ => (),
+            }
+        }
+        reddit(request, base)
+    })
+}
+
+fn report(sandbox: &Sandbox) -> serde_json::Value {
+    read_json(sandbox, "failures-tester.json")
+}
+
+/// Log of the items that were given up on
+fn dead(sandbox: &Sandbox) -> serde_json::Value {
+    read_json(sandbox, "dead-tester.json")
+}
+
+fn read_json(sandbox: &Sandbox, name: &str) -> serde_json::Value {
+    let contents = fs::read(sandbox.data_dir().join(name)).unwrap();
+    serde_json::from_slice(&contents).unwrap()
+}
+
+#[test]
+fn failed_posts_and_media_are_retried_without_the_saved_listing() {
+    let broken = Arc::new(AtomicBool::new(true));
+    let server = unreliable(broken.clone());
+    let sandbox = Sandbox::new();
+    let failing_run = || {
+        let mut command = sandbox.reddsaver(&server);
+        command.arg("--unsave").arg("--retry-attempts").arg("1");
+        command
+    };
+
+    run(&mut failing_run());
+    assert_eq!(report(&sandbox)["failures"].as_array().unwrap().len(), 2);
+    assert!(server.requests_to("POST", "/api/unsave").is_empty());
+    let pages = server.requests_to("GET", "/user/tester/saved").len();
+
+    broken.store(false, Ordering::SeqCst);
+    run(failing_run().arg("retry"));
+
+    assert_eq!(server.requests_to("GET", "/user/tester/saved").len(), pages);
+    assert_eq!(server.requests_to("GET", "/media/abc.jpg").len(), 1);
+    assert_eq!(server.requests_to("GET", "/by_id/t3_second").len(), 1);
+    assert_eq!(
+        files(&sandbox.data_dir()),
+        vec![
+            b"image abc".to_vec(),
+            b"image def".to_vec(),
+            b"video happydog".to_vec()
+        ]
+    );
+    // once everything is downloaded, the posts are unsaved and nothing is left to report
+    let mut unsaved: Vec<String> = server
+        .requests_to("POST", "/api/unsave")
+        .iter()
+        .map(|r| r.param("id").unwrap())
+        .collect();
+    unsaved.sort();
+    assert_eq!(unsaved, vec!["t3_first", "t3_second"]);
+    assert!(!sandbox.data_dir().join("failures-tester.json").exists());
+}
+
+#[test]
+fn items_failing_too_often_are_given_up_on() {
+    let server = unreliable(Arc::new(AtomicBool::new(true)));
+    let sandbox = Sandbox::new();
+    let command = || {
+        let mut command = sandbox.reddsaver(&server);
+        command.arg("--retry-attempts").arg("1");
+        command
+    };
+
+    run(&mut command());
+    run(command().arg("retry").arg("--max-attempts").arg("2"));
+
+    // they are moved from the report to the log of dead items
+    assert!(!sandbox.data_dir().join("failures-tester.json").exists());
+    let dead = dead(&sandbox);
+    let dead = dead["failures"].as_array().unwrap();
+    assert_eq!(dead.len(), 2);
+    for failure in dead {
+        assert_eq!(failure["attempts"], 2);
+        assert_eq!(failure["dead"], true);
+    }
+
+    run(command().arg("retry").arg("--max-attempts").arg("2"));
+    assert_eq!(server.requests_to("GET", "/media/def.jpg").len(), 2);
+    assert_eq!(server.requests_to("GET", "/by_id/t3_second").len(), 1);
+}
+
+#[test]
+fn failures_of_posts_left_out_of_a_run_are_kept() {
+    let broken = Arc::new(AtomicBool::new(true));
+    let server = unreliable(broken.clone());
+    let sandbox = Sandbox::new();
+    let command = || {
+        let mut command = sandbox.reddsaver(&server);
+        command.arg("--retry-attempts").arg("1");
+        command
+    };
+
+    run(&mut command());
+    broken.store(false, Ordering::SeqCst);
+    // the Gfycat post is in another subreddit, so this run does not attempt it again
+    run(command().arg("--subreddits").arg("pics"));
+
+    let report = report(&sandbox);
+    let failures = report["failures"].as_array().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0]["post"], "t3_second");
+    assert_eq!(failures[0]["attempts"], 1);
+}
+
+#[test]
+fn failed_media_without_a_file_name_are_retried_with_their_post() {
+    let server = MockServer::start(reddit);
+    let sandbox = Sandbox::new();
+    // e.g. a report edited by hand, which does not say where the media was going to be saved
+    let report = serde_json::json!({
+        "account": USERNAME,
+        "failures": [{
+            "post": "t3_first",
+            "subreddit": "pics",
+            "url": format!("{}/media/abc.jpg", server.url()),
+            "file_name": null,
+            "error": "HTTP 503",
+        }],
+    });
+    fs::write(
+        sandbox.data_dir().join("failures-tester.json"),
+        report.to_string(),
+    )
+    .unwrap();
+
+    run(sandbox.reddsaver(&server).arg("retry"));
+
+    assert_eq!(server.requests_to("GET", "/by_id/t3_first").len(), 1);
+    assert_eq!(
+        files(&sandbox.data_dir()),
+        vec![b"image abc".to_vec(), b"image def".to_vec()]
+    );
+    assert!(!sandbox.data_dir().join("failures-tester.json").exists());
+}